@@ -3,25 +3,24 @@
 //! ```
 
 mod orderbook;
-use crate::orderbook::run_reporter_poll;
-use tokio::time::Duration;
+use crate::orderbook::{DealArchive, Exchange, FsyncPolicy, JournalConfig, Retention, run_expiry_poll, run_reporter_poll, run_snapshot_poll, SimpleBroker, SlowConsumerPolicy};
+use std::sync::Arc;
+use std::time::Duration;
 use std::env;
 
 use async_graphql::{
     http::{playground_source, GraphQLPlaygroundConfig},
-    EmptyMutation, EmptySubscription, Request, Response, Schema,
+    Schema,
 };
 use async_graphql_axum::{GraphQLRequest, GraphQLResponse, GraphQLSubscription};
 use axum::{
     extract::Extension,
     response::{Html, IntoResponse},
     routing::get,
-    Json, Router,
-    http::Method,
+    Router,
 };
-use std::net::SocketAddr;
-use tower_http::cors::{Any, CorsLayer, Origin};
-use crate::orderbook::{OrderBookSchema, QueryRoot, SubscriptionRoot};
+use tower_http::cors::{Any, CorsLayer};
+use crate::orderbook::{MutationRoot, OrderBookSchema, QueryRoot, SubscriptionRoot};
 
 //async fn graphql_handler(schema: Extension<OrderBookSchema>, req: GraphQLRequest) -> GraphQLResponse {
 async fn graphql_handler(schema: Extension<OrderBookSchema>, req: GraphQLRequest) -> GraphQLResponse {
@@ -35,9 +34,33 @@ async fn graphql_playground() -> impl IntoResponse {
 #[tokio::main]
 async fn main() {
 
-    let PORT = env::var("PORT").unwrap_or("3000".to_string());
+    let port = env::var("PORT").unwrap_or_else(|_| "3000".to_string());
+    // let the mock order generator use market orders to drain the book
+    let reporter_market_orders = env::var("REPORTER_MARKET_ORDERS").is_ok_and(|v| v == "1" || v == "true");
+    // comma separated symbols of the mock instruments
+    let symbols = env::var("MARKETS").unwrap_or_else(|_| "MOCK".to_string());
+    // messages a subscriber can fall behind by, and what happens after that
+    let broker_capacity = env::var("BROKER_CAPACITY").ok().map(|v| v.parse::<usize>().expect("BROKER_CAPACITY must be a number")).unwrap_or(1024);
+    let slow_consumer_policy = env::var("BROKER_SLOW_CONSUMER").ok().map(|v| v.parse::<SlowConsumerPolicy>().unwrap()).unwrap_or(SlowConsumerPolicy::DropOldest);
+    let broker = SimpleBroker::new(broker_capacity, slow_consumer_policy);
+    // deals kept per market, by count (0 for no limit) and by age, and the file evicted deals are appended to
+    let retention = Retention {
+        max_deals: env::var("HISTORY_MAX_DEALS").ok().map(|v| v.parse::<usize>().expect("HISTORY_MAX_DEALS must be a number")).map_or(Retention::default().max_deals, |v| (v > 0).then_some(v)),
+        max_age: env::var("HISTORY_MAX_AGE_SECS").ok().map(|v| chrono::Duration::seconds(v.parse::<i64>().expect("HISTORY_MAX_AGE_SECS must be a number"))),
+        archive: env::var("HISTORY_ARCHIVE").ok().map(|path| DealArchive::open(&path).expect("cannot open HISTORY_ARCHIVE")),
+    };
+    // directory of the market journals and snapshots, when journals are synced to disk, and how often snapshots are taken;
+    // the markets are recovered from them unless started with --fresh
+    let journal = env::var("JOURNAL_DIR").ok().map(|dir| JournalConfig {
+        dir: dir.into(),
+        fsync: env::var("JOURNAL_FSYNC").ok().map(|v| v.parse::<FsyncPolicy>().unwrap()).unwrap_or(FsyncPolicy::Always),
+        fresh: env::args().any(|arg| arg == "--fresh"),
+    });
+    let snapshot_interval = env::var("SNAPSHOT_INTERVAL_SECS").ok().map(|v| v.parse::<u64>().expect("SNAPSHOT_INTERVAL_SECS must be a number")).unwrap_or(60);
+    let exchange = Arc::new(Exchange::new(&symbols.split(',').map(str::trim).filter(|s| !s.is_empty()).collect::<Vec<&str>>(), broker, retention, journal));
 
-    let schema = Schema::build(QueryRoot, EmptyMutation, SubscriptionRoot)
+    let schema = Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+        .data(exchange.clone())
         .finish();
 
     let app = Router::new()
@@ -50,11 +73,19 @@ async fn main() {
                    .allow_headers(Any),
         );
 
-    println!("{}", format!("Playground: http://localhost:{}", &PORT));
+    println!("Playground: http://localhost:{}", &port);
 
 
 
-    tokio::join!(run_reporter_poll(), axum::Server::bind(&format!("0.0.0.0:{}", &PORT).parse().unwrap())
-        .serve(app.into_make_service()));
+    let pollers = async {
+        tokio::join!(run_reporter_poll(exchange.clone(), reporter_market_orders), run_expiry_poll(exchange.clone()), run_snapshot_poll(exchange, Duration::from_secs(snapshot_interval.max(1))))
+    };
+    let server = axum::Server::bind(&format!("0.0.0.0:{}", &port).parse().unwrap())
+        .serve(app.into_make_service());
+    // the pollers never end, the server only does on an error, which must end the process
+    tokio::select! {
+        _ = pollers => {}
+        served = server => served.unwrap(),
+    }
 
 }
\ No newline at end of file
@@ -0,0 +1,208 @@
+use std::cmp::min;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use chrono::{DateTime, FixedOffset};
+use crate::orderbook::model::{Order, OrderType};
+use crate::orderbook::types::big_uint::MyBigUint;
+
+/// Resting orders at one price, oldest first
+#[derive(Clone, Default)]
+pub(crate) struct PriceLevel {
+    /// sum of the visible quantities of the orders at this level
+    pub(crate) quantity: usize,
+    /// iceberg reserves, never shown but still fillable
+    hidden_quantity: usize,
+    /// arrival sequence -> order id
+    queue: BTreeMap<u64, usize>,
+}
+
+impl PriceLevel {
+    pub(crate) fn order_count(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+/// One side of the book: price levels in an ordered map, each a FIFO queue of order ids
+#[derive(Clone)]
+pub(crate) struct BookSide {
+    kind: OrderType,
+    levels: BTreeMap<MyBigUint, PriceLevel>,
+    orders: HashMap<usize, Order>,
+    /// cached so that the top of the book is O(1)
+    best: Option<MyBigUint>,
+    /// good-till-date orders by expiry
+    expiries: BTreeSet<(DateTime<FixedOffset>, usize)>,
+}
+
+impl BookSide {
+    pub(crate) fn with_capacity(kind: OrderType, capacity: usize) -> Self {
+        BookSide {
+            kind,
+            levels: BTreeMap::new(),
+            orders: HashMap::with_capacity(capacity),
+            best: None,
+            expiries: BTreeSet::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.orders.len()
+    }
+
+    /// Highest bid or lowest ask
+    pub(crate) fn best_price(&self) -> Option<&MyBigUint> {
+        self.best.as_ref()
+    }
+
+    /// Oldest order at the best price
+    pub(crate) fn best_order(&self) -> Option<&Order> {
+        let level = self.levels.get(self.best.as_ref()?)?;
+        level.queue.values().next().and_then(|id| self.orders.get(id))
+    }
+
+    pub(crate) fn get(&self, id: usize) -> Option<&Order> {
+        self.orders.get(&id)
+    }
+
+    /// Whether a resting order at `price` trades against an incoming `limit`, market orders cross everything
+    pub(crate) fn crosses(&self, price: &MyBigUint, limit: Option<&MyBigUint>) -> bool {
+        limit.is_none_or(|limit| match self.kind {
+            OrderType::Buy => price >= limit,
+            OrderType::Sell => price <= limit,
+        })
+    }
+
+    /// Quantity an incoming order with `limit` could take from this side, counting no further than `up_to`
+    pub(crate) fn fillable_quantity(&self, limit: Option<&MyBigUint>, up_to: usize) -> usize {
+        let mut available = 0;
+        for (price, level) in self.levels() {
+            if available >= up_to || !self.crosses(price, limit) {
+                break;
+            }
+            available = available.saturating_add(level.quantity).saturating_add(level.hidden_quantity);
+        }
+        available
+    }
+
+    /// Ids of orders that expire at or before `now`
+    pub(crate) fn expired(&self, now: &DateTime<FixedOffset>) -> Vec<usize> {
+        self.expiries.range(..=(*now, usize::MAX)).map(|(_, id)| *id).collect()
+    }
+
+    fn is_better(&self, price: &MyBigUint, than: &MyBigUint) -> bool {
+        match self.kind {
+            OrderType::Buy => price > than,
+            OrderType::Sell => price < than,
+        }
+    }
+
+    fn first_level_price(&self) -> Option<MyBigUint> {
+        match self.kind {
+            OrderType::Buy => self.levels.keys().next_back().cloned(),
+            OrderType::Sell => self.levels.keys().next().cloned(),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, order: Order) {
+        let level = self.levels.entry(order.data.price.clone()).or_default();
+        level.quantity = level.quantity.saturating_add(order.data.quantity);
+        level.hidden_quantity = level.hidden_quantity.saturating_add(order.hidden_quantity);
+        level.queue.insert(order.seq, order.id);
+        if self.best.as_ref().is_none_or(|best| self.is_better(&order.data.price, best)) {
+            self.best = Some(order.data.price.clone());
+        }
+        if let Some(expires_at) = &order.expires_at {
+            self.expiries.insert((expires_at.0, order.id));
+        }
+        self.orders.insert(order.id, order);
+    }
+
+    pub(crate) fn remove(&mut self, id: usize) -> Option<Order> {
+        let order = self.orders.remove(&id)?;
+        let level = self.levels.get_mut(&order.data.price).expect("order without a price level");
+        level.quantity = level.quantity.saturating_sub(order.data.quantity);
+        level.hidden_quantity = level.hidden_quantity.saturating_sub(order.hidden_quantity);
+        level.queue.remove(&order.seq);
+        if let Some(expires_at) = &order.expires_at {
+            self.expiries.remove(&(expires_at.0, order.id));
+        }
+        if level.queue.is_empty() {
+            self.levels.remove(&order.data.price);
+            if self.best.as_ref() == Some(&order.data.price) {
+                self.best = self.first_level_price();
+            }
+        }
+        Some(order)
+    }
+
+    /// Change the remaining quantity of a resting order without touching its queue position
+    pub(crate) fn set_quantity(&mut self, id: usize, quantity: usize) -> Option<&Order> {
+        let order = self.orders.get_mut(&id)?;
+        let level = self.levels.get_mut(&order.data.price).expect("order without a price level");
+        level.quantity = level.quantity.saturating_sub(order.data.quantity).saturating_add(quantity);
+        order.data.quantity = quantity;
+        Some(order)
+    }
+
+    /// Show the next slice of an iceberg whose visible part is used up; the slice goes to the back
+    /// of its level under the new arrival sequence `seq`
+    pub(crate) fn replenish(&mut self, id: usize, seq: u64) -> Option<&Order> {
+        let order = self.orders.get_mut(&id)?;
+        let level = self.levels.get_mut(&order.data.price).expect("order without a price level");
+        let slice = min(order.display_quantity.unwrap_or(0), order.hidden_quantity);
+        level.quantity = level.quantity.saturating_sub(order.data.quantity).saturating_add(slice);
+        level.hidden_quantity = level.hidden_quantity.saturating_sub(slice);
+        level.queue.remove(&order.seq);
+        level.queue.insert(seq, id);
+        order.data.quantity = slice;
+        order.hidden_quantity -= slice;
+        order.seq = seq;
+        Some(order)
+    }
+
+    /// Price levels, best first
+    pub(crate) fn levels(&self) -> Box<dyn Iterator<Item = (&MyBigUint, &PriceLevel)> + '_> {
+        match self.kind {
+            OrderType::Buy => Box::new(self.levels.iter().rev()),
+            OrderType::Sell => Box::new(self.levels.iter()),
+        }
+    }
+
+    /// Orders in matching priority: best price first, then oldest first
+    pub(crate) fn orders(&self) -> impl Iterator<Item = &Order> + '_ {
+        self.levels()
+            .flat_map(|(_, level)| level.queue.values())
+            .map(move |id| &self.orders[id])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+    use crate::orderbook::book_side::BookSide;
+    use crate::orderbook::model::{Order, OrderCommons, OrderType};
+    use crate::orderbook::types::big_uint::MyBigUint;
+
+    fn order(id: usize, quantity: usize) -> Order {
+        Order {
+            id,
+            data: OrderCommons { quantity, price: MyBigUint(BigUint::from(100u32)) },
+            kind: OrderType::Sell,
+            seq: id as u64,
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            post_only: None,
+        }
+    }
+
+    #[test]
+    fn level_totals_saturate() {
+        let mut side = BookSide::with_capacity(OrderType::Sell, 2);
+        side.insert(order(0, usize::MAX));
+        side.insert(order(1, usize::MAX));
+        assert_eq!(side.fillable_quantity(None, usize::MAX), usize::MAX);
+        side.remove(0);
+        side.remove(1);
+        assert_eq!(side.best_price(), None);
+    }
+}
@@ -0,0 +1,126 @@
+use std::cmp::{max, min};
+use std::collections::{HashMap, VecDeque};
+use async_graphql::{Enum, SimpleObject};
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, FixedOffset, TimeZone};
+use crate::orderbook::model::Deal;
+use crate::orderbook::types::big_uint::MyBigUint;
+use crate::orderbook::types::date_time::MyDateTime;
+
+/// Bars kept per interval
+const CANDLE_CAPACITY: usize = 1_440;
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Enum, Serialize, Deserialize)]
+pub(crate) enum CandleInterval {
+    OneSecond,
+    OneMinute,
+    FiveMinutes,
+    OneHour,
+    OneDay,
+}
+
+impl CandleInterval {
+    const ALL: [CandleInterval; 5] = [
+        CandleInterval::OneSecond,
+        CandleInterval::OneMinute,
+        CandleInterval::FiveMinutes,
+        CandleInterval::OneHour,
+        CandleInterval::OneDay,
+    ];
+
+    fn seconds(self) -> i64 {
+        match self {
+            CandleInterval::OneSecond => 1,
+            CandleInterval::OneMinute => 60,
+            CandleInterval::FiveMinutes => 5 * 60,
+            CandleInterval::OneHour => 60 * 60,
+            CandleInterval::OneDay => 24 * 60 * 60,
+        }
+    }
+
+    /// Start of the bar `time` falls in; bars are aligned to the epoch, days start at midnight UTC
+    fn bar_start(self, time: &DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+        let timestamp = time.timestamp();
+        FixedOffset::east(0).timestamp(timestamp - timestamp.rem_euclid(self.seconds()), 0)
+    }
+}
+
+/// Open, high, low, close and volume of the deals in one interval
+#[derive(Clone, Debug, SimpleObject, Serialize, Deserialize)]
+pub(crate) struct Candle {
+    pub(crate) symbol: String,
+    pub(crate) interval: CandleInterval,
+    pub(crate) open_time: MyDateTime<FixedOffset>,
+    pub(crate) open: MyBigUint,
+    pub(crate) high: MyBigUint,
+    pub(crate) low: MyBigUint,
+    pub(crate) close: MyBigUint,
+    /// traded quantity
+    pub(crate) volume: usize,
+    pub(crate) trades: usize,
+}
+
+impl Candle {
+    fn first(interval: CandleInterval, open_time: DateTime<FixedOffset>, d: &Deal) -> Self {
+        Candle {
+            symbol: d.symbol.clone(),
+            interval,
+            open_time: MyDateTime(open_time),
+            open: d.price.clone(),
+            high: d.price.clone(),
+            low: d.price.clone(),
+            close: d.price.clone(),
+            volume: d.quantity,
+            trades: 1,
+        }
+    }
+
+    fn add(&mut self, d: &Deal) {
+        self.high = max(&self.high, &d.price).clone();
+        self.low = min(&self.low, &d.price).clone();
+        self.close = d.price.clone();
+        self.volume = self.volume.saturating_add(d.quantity);
+        self.trades += 1;
+    }
+}
+
+/// OHLCV bars of a market for every interval, oldest first; intervals without deals have no bar
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Candles {
+    bars: HashMap<CandleInterval, VecDeque<Candle>>,
+}
+
+impl Candles {
+    pub(crate) fn new() -> Self {
+        Candles {
+            bars: CandleInterval::ALL.iter().map(|interval| (*interval, VecDeque::new())).collect(),
+        }
+    }
+
+    /// Add a deal to the current bar of each interval, returning those bars
+    pub(crate) fn record(&mut self, d: &Deal) -> Vec<Candle> {
+        CandleInterval::ALL.iter().map(|interval| {
+            let bars = self.bars.get_mut(interval).unwrap();
+            let open_time = interval.bar_start(&d.created_at.0);
+            match bars.back_mut() {
+                // deals come in time order, a deal never lands in an earlier bar
+                Some(bar) if bar.open_time.0 >= open_time => bar.add(d),
+                _ => {
+                    if bars.len() >= CANDLE_CAPACITY {
+                        bars.pop_front();
+                    }
+                    bars.push_back(Candle::first(*interval, open_time, d));
+                }
+            }
+            bars.back().unwrap().clone()
+        }).collect()
+    }
+
+    /// Bars of `interval` opened in [`from`, `to`)
+    pub(crate) fn range(&self, interval: CandleInterval, from: Option<&DateTime<FixedOffset>>, to: Option<&DateTime<FixedOffset>>) -> Vec<Candle> {
+        self.bars[&interval].iter()
+            .filter(|bar| from.is_none_or(|from| bar.open_time.0 >= *from) && to.is_none_or(|to| bar.open_time.0 < *to))
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::Duration;
+use futures_util::stream::{self, BoxStream};
+use futures_util::{Stream, StreamExt};
+use tokio::time::{self, Instant};
+
+/// Merge the items of `updates` arriving within `period` of the last one sent into a single item,
+/// so that at most one goes out per period; the first item after a quiet period goes out at once
+pub(crate) fn conflate<T, S, F>(updates: S, period: Duration, merge: F) -> impl Stream<Item = T>
+    where
+        T: Send + 'static,
+        S: Stream<Item = T> + Send + 'static,
+        F: FnMut(T, T) -> T + Send + 'static,
+{
+    let state = (updates.boxed().fuse(), None, Instant::now(), merge);
+    stream::unfold(state, move |(mut updates, mut pending, next_send, mut merge)| async move {
+        loop {
+            if pending.is_some() && Instant::now() >= next_send {
+                let item = pending.take()?;
+                return Some((item, (updates, None, Instant::now() + period, merge)));
+            }
+            tokio::select! {
+                update = updates.next() => match update {
+                    Some(update) => {
+                        pending = Some(match pending.take() {
+                            Some(previous) => merge(previous, update),
+                            None => update,
+                        });
+                    }
+                    // the source ended: flush what is left
+                    None => return pending.map(|item| (item, (updates, None, next_send, merge))),
+                },
+                _ = time::sleep_until(next_send), if pending.is_some() => {}
+            }
+        }
+    })
+}
+
+/// `conflate` with a period in milliseconds, passing every item through when there is none
+pub(crate) fn throttle<T, S, F>(updates: S, throttle_ms: Option<u64>, merge: F) -> BoxStream<'static, T>
+    where
+        T: Send + 'static,
+        S: Stream<Item = T> + Send + 'static,
+        F: FnMut(T, T) -> T + Send + 'static,
+{
+    match throttle_ms {
+        Some(ms) if ms > 0 => conflate(updates, Duration::from_millis(ms), merge).boxed(),
+        _ => updates.boxed(),
+    }
+}
+
+/// Lift `merge` to results: merged values while both are fine, otherwise the error
+pub(crate) fn merge_ok<T, E>(mut merge: impl FnMut(T, T) -> T) -> impl FnMut(Result<T, E>, Result<T, E>) -> Result<T, E> {
+    move |earlier, later| match (earlier, later) {
+        (Ok(earlier), Ok(later)) => Ok(merge(earlier, later)),
+        (Err(e), _) | (_, Err(e)) => Err(e),
+    }
+}
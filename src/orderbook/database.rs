@@ -1,40 +1,175 @@
-use std::collections::{BinaryHeap, VecDeque};
-use once_cell::sync::Lazy;
-use std::sync::Mutex;
-use slab::Slab;
-use crate::orderbook::model::Deal;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use arc_swap::ArcSwap;
+use tokio::sync::mpsc;
+use crate::orderbook::book_side::BookSide;
+use crate::orderbook::candles::Candles;
+use crate::orderbook::engine::{Command, run_engine};
+use crate::orderbook::journal::{Journal, JournalEntry, JournalPosition};
+use crate::orderbook::matcher::now;
+use crate::orderbook::model::{Deal, OrderType};
 use crate::orderbook::model::OrderBook;
+use crate::orderbook::retention::Retention;
+use crate::orderbook::simple_broker::SimpleBroker;
+use crate::orderbook::stop_book::StopBook;
+use crate::orderbook::ticker::{DealStats, Ticker};
 
 pub const ORDERBOOK_CAPACITY: usize = 50;
-pub const HISTORY_CAPACITY: usize = 10_000;
-pub struct OrderBookData {
+/// commands a market's engine can have queued before submitters wait
+const COMMAND_CAPACITY: usize = 1024;
+
+/// An event held back until the batch that caused it is journaled
+type Event = Box<dyn FnOnce(&SimpleBroker, &str) + Send>;
+
+/// Everything a market's engine thread owns
+pub struct MarketState {
     pub(crate) orderbook: OrderBook,
+    pub(crate) history: HistoryData,
+    /// sequence number of the last book update published
+    pub(crate) sequence: u64,
 }
 
-impl OrderBookData {
+impl MarketState {
     pub fn new() -> Self {
-        OrderBookData {
+        MarketState {
             orderbook: OrderBook {
-                bids: BinaryHeap::with_capacity(ORDERBOOK_CAPACITY),
-                asks: BinaryHeap::with_capacity(ORDERBOOK_CAPACITY),
-                bid_map: Slab::with_capacity(ORDERBOOK_CAPACITY),
-                ask_map: Slab::with_capacity(ORDERBOOK_CAPACITY),
+                bids: BookSide::with_capacity(OrderType::Buy, ORDERBOOK_CAPACITY),
+                asks: BookSide::with_capacity(OrderType::Sell, ORDERBOOK_CAPACITY),
+                stops: StopBook::default(),
+                next_id: 0,
+                next_seq: 0,
             },
+            history: HistoryData::new(),
+            sequence: 0,
+        }
+    }
+
+    /// Summary as of now; moves the 24 hour window along
+    pub(crate) fn ticker(&mut self, symbol: &str) -> Ticker {
+        Ticker::new(symbol, &mut self.history.stats, &self.orderbook, &now())
+    }
+
+    pub(crate) fn snapshot(&self) -> BookSnapshot {
+        BookSnapshot {
+            sequence: self.sequence,
+            orderbook: self.orderbook.clone(),
         }
     }
 }
 
+/// Immutable copy of a book as of book update `sequence`
+pub struct BookSnapshot {
+    pub(crate) sequence: u64,
+    pub(crate) orderbook: OrderBook,
+}
+
+/// What the market keeps of its deals
 pub struct HistoryData {
     pub(crate) deals_history: VecDeque<Deal>,
+    pub(crate) candles: Candles,
+    pub(crate) stats: DealStats,
+    /// sequence number of the next deal, the cursor of the history
+    pub(crate) next_seq: usize,
 }
 
 impl HistoryData {
     pub fn new() -> Self {
         HistoryData {
-            deals_history: VecDeque::with_capacity(HISTORY_CAPACITY),
+            deals_history: VecDeque::new(),
+            candles: Candles::new(),
+            stats: DealStats::default(),
+            next_seq: 0,
         }
     }
 }
 
-pub static ORDERBOOK_STATE: Lazy<Mutex<OrderBookData>> = Lazy::new(|| Mutex::new(OrderBookData::new()));
-pub static HISTORY_STATE: Lazy<Mutex<HistoryData>> = Lazy::new(|| Mutex::new(HistoryData::new()));
\ No newline at end of file
+/// One instrument; its book and deal history belong to an engine thread that is reached through `commands`
+pub struct Market {
+    pub(crate) symbol: String,
+    /// shared with the exchange, carries this market's events
+    pub(crate) broker: SimpleBroker,
+    /// limits of the deal history
+    pub(crate) retention: Retention,
+    /// only the engine thread writes to it, so the lock is never contended
+    journal: Option<Mutex<Journal>>,
+    /// published during the engine's current batch
+    events: Mutex<Vec<Event>>,
+    pub(crate) commands: mpsc::Sender<Command>,
+    /// replaced by the engine after each batch of mutations, read without locking
+    pub(crate) snapshot: ArcSwap<BookSnapshot>,
+    /// recomputed by the engine after each batch of commands
+    pub(crate) ticker: ArcSwap<Ticker>,
+}
+
+impl Market {
+    /// Create the market from `state` and start its engine thread, which stops once the market is dropped
+    pub(crate) fn new(symbol: &str, broker: SimpleBroker, retention: Retention, mut state: MarketState, journal: Option<Journal>) -> Arc<Self> {
+        Arc::new_cyclic(|market| {
+            let (commands, receiver) = mpsc::channel(COMMAND_CAPACITY);
+            let snapshot = ArcSwap::from_pointee(state.snapshot());
+            let ticker = ArcSwap::from_pointee(state.ticker(symbol));
+            let market = market.clone();
+            thread::Builder::new()
+                .name(format!("engine-{}", symbol))
+                .spawn(move || run_engine(market, state, receiver))
+                .expect("cannot start the engine thread");
+            Market {
+                symbol: symbol.to_string(),
+                broker,
+                retention,
+                journal: journal.map(Mutex::new),
+                events: Mutex::new(Vec::new()),
+                commands,
+                snapshot,
+                ticker,
+            }
+        })
+    }
+
+    /// Record what the engine did, if the market keeps a journal
+    pub(crate) fn journal(&self, entry: impl FnOnce() -> JournalEntry) {
+        if let Some(journal) = &self.journal {
+            journal.lock().unwrap().append(entry());
+        }
+    }
+
+    /// Queue one of this market's events for its subscribers, who get it once the engine's batch is journaled
+    pub(crate) fn publish<T: Sync + Send + Clone + 'static>(&self, event: T) {
+        self.events.lock().unwrap().push(Box::new(move |broker, symbol| broker.publish(symbol, event)));
+    }
+
+    /// Send the events of the engine's last batch, in the order they were published
+    pub(crate) fn send_events(&self) {
+        let events = std::mem::take(&mut *self.events.lock().unwrap());
+        events.into_iter().for_each(|event| event(&self.broker, &self.symbol));
+    }
+
+    /// Sync the journal and tell where it ends; it matches the engine's state once a batch is committed
+    pub(crate) fn sync_journal(&self) -> Option<io::Result<JournalPosition>> {
+        self.journal.as_ref().map(|journal| journal.lock().unwrap().sync())
+    }
+
+    /// Write out the engine's last batch: the deals it archived, then its journal records
+    pub(crate) fn commit_journal(&self) -> io::Result<()> {
+        if let Some(archive) = &self.retention.archive {
+            archive.commit()?;
+        }
+        self.journal.as_ref().map_or(Ok(()), |journal| journal.lock().unwrap().commit())
+    }
+
+    /// Latest published state of the book
+    pub(crate) fn snapshot(&self) -> Arc<BookSnapshot> {
+        self.snapshot.load_full()
+    }
+}
+
+#[cfg(test)]
+impl Market {
+    /// A market with a broker of its own and nothing kept on disk
+    pub(crate) fn in_memory(symbol: &str) -> Arc<Self> {
+        use crate::orderbook::simple_broker::SlowConsumerPolicy;
+        Market::new(symbol, SimpleBroker::new(1024, SlowConsumerPolicy::DropOldest), Retention::default(), MarketState::new(), None)
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::{BTreeMap, HashMap};
+use async_graphql::SimpleObject;
+use crate::orderbook::model::{BookUpdate, BookUpdateKind, OrderBook, OrderType};
+use crate::orderbook::types::big_uint::MyBigUint;
+
+/// New total visible quantity at one price; 0 once the level is gone or no longer among the top levels
+#[derive(Clone, Debug, SimpleObject)]
+pub(crate) struct LevelChange {
+    pub(crate) side: OrderType,
+    pub(crate) price: MyBigUint,
+    pub(crate) quantity: usize,
+}
+
+#[derive(Clone, SimpleObject)]
+pub(crate) struct DepthUpdate {
+    pub(crate) symbol: String,
+    /// sequence number of the last book update reflected
+    pub(crate) sequence: u64,
+    /// set when the changes list every level in view and replace what came before:
+    /// on the first message, and after updates were lost to a full subscriber queue
+    pub(crate) snapshot: bool,
+    pub(crate) changes: Vec<LevelChange>,
+}
+
+impl DepthUpdate {
+    /// Fold a later update into this one, keeping the latest quantity of each level
+    pub(crate) fn merge(mut self, later: DepthUpdate) -> Self {
+        if later.snapshot {
+            return later;
+        }
+        for change in later.changes {
+            match self.changes.iter_mut().find(|existing| existing.side == change.side && existing.price == change.price) {
+                Some(existing) => existing.quantity = change.quantity,
+                None => self.changes.push(change),
+            }
+        }
+        if self.snapshot {
+            self.changes.retain(|change| change.quantity > 0);
+        }
+        DepthUpdate { sequence: later.sequence, ..self }
+    }
+}
+
+/// Price levels of a book rebuilt from its order events, tracking what the top `levels` show
+pub(crate) struct DepthView {
+    levels: usize,
+    /// resting order id -> (side, price, visible quantity)
+    orders: HashMap<usize, (OrderType, MyBigUint, usize)>,
+    bids: BTreeMap<MyBigUint, usize>,
+    asks: BTreeMap<MyBigUint, usize>,
+}
+
+impl DepthView {
+    pub(crate) fn new(book: &OrderBook, levels: usize) -> Self {
+        let mut view = DepthView {
+            levels,
+            orders: HashMap::new(),
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+        };
+        for order in book.bids.orders().chain(book.asks.orders()) {
+            view.add(order.id, order.kind, &order.data.price, order.data.quantity);
+        }
+        view
+    }
+
+    fn side(&mut self, kind: OrderType) -> &mut BTreeMap<MyBigUint, usize> {
+        match kind {
+            OrderType::Buy => &mut self.bids,
+            OrderType::Sell => &mut self.asks,
+        }
+    }
+
+    fn add(&mut self, id: usize, kind: OrderType, price: &MyBigUint, quantity: usize) {
+        let total = self.side(kind).entry(price.clone()).or_default();
+        *total = total.saturating_add(quantity);
+        self.orders.insert(id, (kind, price.clone(), quantity));
+    }
+
+    fn remove(&mut self, id: usize) {
+        if let Some((kind, price, quantity)) = self.orders.remove(&id) {
+            let side = self.side(kind);
+            let total = side.get_mut(&price).expect("order without a price level");
+            *total = total.saturating_sub(quantity);
+            if *total == 0 {
+                side.remove(&price);
+            }
+        }
+    }
+
+    /// Levels of one side, best first
+    fn iter(&self, kind: OrderType) -> Box<dyn Iterator<Item = (&MyBigUint, &usize)> + '_> {
+        match kind {
+            OrderType::Buy => Box::new(self.bids.iter().rev()),
+            OrderType::Sell => Box::new(self.asks.iter()),
+        }
+    }
+
+    /// Top levels of one side, best first
+    fn top(&self, kind: OrderType) -> Vec<(MyBigUint, usize)> {
+        self.iter(kind).take(self.levels).map(|(price, quantity)| (price.clone(), *quantity)).collect()
+    }
+
+    /// Every level in view
+    pub(crate) fn levels(&self) -> Vec<LevelChange> {
+        [OrderType::Buy, OrderType::Sell].into_iter().flat_map(|side| {
+            self.top(side).into_iter().map(move |(price, quantity)| LevelChange { side, price, quantity })
+        }).collect()
+    }
+
+    /// Worst price in view when the side has enough levels to fill the view, none when all of them are in it
+    fn edge(&self, kind: OrderType) -> Option<MyBigUint> {
+        self.levels.checked_sub(1).and_then(|last| self.iter(kind).nth(last)).map(|(price, _)| price.clone())
+    }
+
+    fn quantity(&self, kind: OrderType, price: &MyBigUint) -> usize {
+        let side = match kind {
+            OrderType::Buy => &self.bids,
+            OrderType::Sell => &self.asks,
+        };
+        side.get(price).copied().unwrap_or(0)
+    }
+
+    /// What the view shows of a level with `quantity`, given the edge of the view
+    fn visible(&self, kind: OrderType, price: &MyBigUint, quantity: usize, edge: &Option<MyBigUint>) -> usize {
+        let in_view = self.levels > 0 && edge.as_ref().is_none_or(|edge| match kind {
+            OrderType::Buy => price >= edge,
+            OrderType::Sell => price <= edge,
+        });
+        if in_view { quantity } else { 0 }
+    }
+
+    /// Apply a book update, returning the changes it makes to the levels in view. Only the levels of the order change,
+    /// and at most one other level moves in or out of view, at its edge
+    pub(crate) fn apply(&mut self, update: &BookUpdate) -> Vec<LevelChange> {
+        let order = match (&update.kind, &update.order) {
+            (BookUpdateKind::Trade, _) | (_, None) => return Vec::new(),
+            (_, Some(order)) => order,
+        };
+        let kind = order.kind;
+        let mut touched = vec![order.data.price.clone()];
+        if let Some((_, price, _)) = self.orders.get(&order.id).filter(|(_, price, _)| *price != order.data.price) {
+            touched.push(price.clone());
+        }
+        let before = touched.iter().map(|price| self.quantity(kind, price)).collect::<Vec<usize>>();
+        let edge_before = self.edge(kind);
+        self.remove(order.id);
+        if update.kind != BookUpdateKind::Remove {
+            self.add(order.id, kind, &order.data.price, order.data.quantity);
+        }
+        let edge_after = self.edge(kind);
+        let mut edges = [edge_before.clone(), edge_after.clone()].into_iter().flatten()
+            .filter(|price| !touched.contains(price))
+            .collect::<Vec<MyBigUint>>();
+        edges.dedup();
+        // the quantity of a level the update did not touch is the same before and after
+        let untouched = edges.into_iter().map(|price| {
+            let quantity = self.quantity(kind, &price);
+            (price, quantity)
+        });
+        touched.into_iter().zip(before).chain(untouched)
+            .filter_map(|(price, before)| {
+                let was = self.visible(kind, &price, before, &edge_before);
+                let quantity = self.visible(kind, &price, self.quantity(kind, &price), &edge_after);
+                (was != quantity).then_some(LevelChange { side: kind, price, quantity })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use num_bigint::BigUint;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::orderbook::database::MarketState;
+    use crate::orderbook::depth::DepthView;
+    use crate::orderbook::model::{BookUpdate, BookUpdateKind, Order, OrderCommons, OrderType};
+    use crate::orderbook::types::big_uint::MyBigUint;
+
+    fn order(id: usize, kind: OrderType, price: u32, quantity: usize) -> Order {
+        Order {
+            id,
+            data: OrderCommons { quantity, price: MyBigUint(BigUint::from(price)) },
+            kind,
+            seq: id as u64,
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            post_only: None,
+        }
+    }
+
+    /// A subscriber that applies the changes it gets ends up with the levels in view, whatever the updates
+    #[test]
+    fn changes_keep_a_copy_of_the_view_in_step() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut view = DepthView::new(&MarketState::new().orderbook, 3);
+        let mut copy = HashMap::new();
+        let mut resting = Vec::<Order>::new();
+        for sequence in 1..2000 {
+            let (kind, order) = match rng.gen_range(0..3) {
+                0 | 1 if resting.is_empty() || rng.gen_bool(0.5) => {
+                    let kind = if rng.gen_bool(0.5) { OrderType::Buy } else { OrderType::Sell };
+                    let order = order(sequence, kind, rng.gen_range(90..110), rng.gen_range(1..10));
+                    resting.push(order.clone());
+                    (BookUpdateKind::Add, order)
+                }
+                0 | 1 => {
+                    let i = rng.gen_range(0..resting.len());
+                    resting[i].data.quantity = rng.gen_range(1..10);
+                    (BookUpdateKind::Update, resting[i].clone())
+                }
+                _ if resting.is_empty() => continue,
+                _ => (BookUpdateKind::Remove, resting.swap_remove(rng.gen_range(0..resting.len()))),
+            };
+            let update = BookUpdate { symbol: "TEST".to_string(), sequence: sequence as u64, kind, order: Some(order), deal: None };
+            for change in view.apply(&update) {
+                copy.insert((change.side, change.price), change.quantity);
+            }
+            copy.retain(|_, quantity| *quantity > 0);
+            let expected = view.levels().into_iter().map(|level| ((level.side, level.price), level.quantity)).collect::<HashMap<_, _>>();
+            assert_eq!(copy, expected, "after update {}", sequence);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::sync::{Arc, Weak};
+use tokio::sync::{mpsc, oneshot};
+use crate::orderbook::database::{Market, MarketState};
+use crate::orderbook::error::OrderBookError;
+use crate::orderbook::journal::JournalEntry;
+use crate::orderbook::matcher::{MatchResult, Matcher, NewOrder, now};
+use crate::orderbook::model::{Order, OrderCommons, OrderType, SnapshotPublished};
+use crate::orderbook::stop_book::StopOrder;
+use crate::orderbook::types::big_uint::MyBigUint;
+
+type Reply<T> = oneshot::Sender<T>;
+
+/// A request to a market's engine thread; mutations are applied one at a time in arrival order
+pub(crate) enum Command {
+    Place { order: NewOrder, reply: Reply<Result<MatchResult, OrderBookError>> },
+    Cancel { kind: OrderType, id: usize, reply: Reply<Result<Order, OrderBookError>> },
+    Modify { kind: OrderType, id: usize, data: OrderCommons, reply: Reply<Result<MatchResult, OrderBookError>> },
+    PlaceStop { kind: OrderType, trigger_price: MyBigUint, limit_price: Option<MyBigUint>, quantity: usize, reply: Reply<StopOrder> },
+    CancelStop { id: usize, reply: Reply<Result<StopOrder, OrderBookError>> },
+    Expire { reply: Reply<Vec<Order>> },
+    /// look at the state once the batch it came in is journaled
+    Read(Box<dyn FnOnce(&MarketState) + Send>),
+}
+
+/// most commands applied before a new snapshot is published
+const MAX_BATCH: usize = 256;
+
+/// A reply held back until the batch is journaled, given the state as the batch left it
+type Answer = Box<dyn FnOnce(&MarketState) + Send>;
+
+fn answer<T: Send + 'static>(answers: &mut Vec<Answer>, reply: Reply<T>, value: T) {
+    // a requester that went away no longer needs the reply
+    answers.push(Box::new(move |_| {
+        reply.send(value).ok();
+    }));
+}
+
+/// Owns a market's state and serves its commands until the market is dropped. It runs on a thread of its own,
+/// as the journal's writes and syncs block it
+pub(crate) fn run_engine(market: Weak<Market>, mut state: MarketState, mut commands: mpsc::Receiver<Command>) {
+    while let Some(command) = commands.blocking_recv() {
+        let market = match market.upgrade() {
+            Some(market) => market,
+            None => break,
+        };
+        let mut answers = Vec::new();
+        let mut changed = apply(&market, &mut state, command, &mut answers);
+        // whatever is already queued joins the batch
+        for _ in 1..MAX_BATCH {
+            match commands.try_recv() {
+                Ok(command) => changed |= apply(&market, &mut state, command, &mut answers),
+                Err(_) => break,
+            }
+        }
+        // the history age limit moves even without deals, the expiry poll makes sure this runs every second;
+        // what it evicts is archived with the batch
+        market.retention.trim(&mut state.history.deals_history, &now());
+        // nobody hears of a command, by its reply or its events, before its records are written;
+        // without a journal the market cannot go on
+        if let Err(e) = market.commit_journal() {
+            eprintln!("journal of {}: {}, closing the market", market.symbol, e);
+            break;
+        }
+        market.send_events();
+        answers.into_iter().for_each(|answer| answer(&state));
+        if changed {
+            let snapshot = Arc::new(state.snapshot());
+            market.snapshot.store(snapshot.clone());
+            market.broker.publish(&market.symbol, SnapshotPublished { snapshot });
+        }
+        // the 24 hour window moves even without deals
+        let ticker = state.ticker(&market.symbol);
+        if ticker != **market.ticker.load() {
+            market.ticker.store(Arc::new(ticker.clone()));
+            market.broker.publish(&market.symbol, ticker);
+        }
+    }
+}
+
+/// Run one command, telling whether it changed the book or its stops; its reply is queued in `answers`
+fn apply(market: &Market, state: &mut MarketState, command: Command, answers: &mut Vec<Answer>) -> bool {
+    // every change to the book has a sequence number; a single command adds stops or takes them off, never both
+    let before = (state.sequence, state.orderbook.stops.len());
+    match command {
+        Command::Place { order, reply } => {
+            let result = Matcher::submit(market, state, &order);
+            if let Ok(result) = &result {
+                market.journal(|| JournalEntry::OrderAccepted { order, id: result.order.as_ref().map(|order| order.id) });
+            }
+            answer(answers, reply, result);
+        }
+        Command::Cancel { kind, id, reply } => {
+            let result = Matcher::cancel(market, state, kind, id);
+            if result.is_ok() {
+                market.journal(|| JournalEntry::OrderCancelled { kind, id });
+            }
+            answer(answers, reply, result);
+        }
+        Command::Modify { kind, id, data, reply } => {
+            let result = Matcher::modify(market, state, kind, id, &data);
+            if let Ok(result) = &result {
+                market.journal(|| JournalEntry::OrderModified { kind, id, data, new_id: result.order.as_ref().map(|order| order.id) });
+            }
+            answer(answers, reply, result);
+        }
+        Command::PlaceStop { kind, trigger_price, limit_price, quantity, reply } => {
+            answer(answers, reply, Matcher::submit_stop(market, state, kind, trigger_price, limit_price, quantity));
+        }
+        Command::CancelStop { id, reply } => {
+            answer(answers, reply, Matcher::cancel_stop(market, state, id));
+        }
+        Command::Expire { reply } => {
+            answer(answers, reply, Matcher::expire(market, state));
+        }
+        // what a read sees must be journaled already, snapshots rely on it
+        Command::Read(read) => {
+            answers.push(read);
+        }
+    }
+    (state.sequence, state.orderbook.stops.len()) != before
+}
+
+/// Async front of the engine: each call queues a command and waits for its reply
+impl Market {
+    async fn request<T>(&self, command: impl FnOnce(Reply<T>) -> Command) -> Result<T, OrderBookError> {
+        let (reply, response) = oneshot::channel();
+        let closed = || OrderBookError::MarketClosed { symbol: self.symbol.clone() };
+        self.commands.send(command(reply)).await.map_err(|_| closed())?;
+        response.await.map_err(|_| closed())
+    }
+
+    pub(crate) async fn submit(&self, order: NewOrder) -> Result<MatchResult, OrderBookError> {
+        self.request(|reply| Command::Place { order, reply }).await?
+    }
+
+    pub(crate) async fn cancel(&self, kind: OrderType, id: usize) -> Result<Order, OrderBookError> {
+        self.request(|reply| Command::Cancel { kind, id, reply }).await?
+    }
+
+    pub(crate) async fn modify(&self, kind: OrderType, id: usize, data: OrderCommons) -> Result<MatchResult, OrderBookError> {
+        self.request(|reply| Command::Modify { kind, id, data, reply }).await?
+    }
+
+    pub(crate) async fn submit_stop(&self, kind: OrderType, trigger_price: MyBigUint, limit_price: Option<MyBigUint>, quantity: usize) -> Result<StopOrder, OrderBookError> {
+        self.request(|reply| Command::PlaceStop { kind, trigger_price, limit_price, quantity, reply }).await
+    }
+
+    pub(crate) async fn cancel_stop(&self, id: usize) -> Result<StopOrder, OrderBookError> {
+        self.request(|reply| Command::CancelStop { id, reply }).await?
+    }
+
+    pub(crate) async fn expire(&self) -> Result<Vec<Order>, OrderBookError> {
+        self.request(|reply| Command::Expire { reply }).await
+    }
+
+    /// Run `read` on the engine thread and return what it computes
+    pub(crate) async fn read<T: Send + 'static>(&self, read: impl FnOnce(&MarketState) -> T + Send + 'static) -> Result<T, OrderBookError> {
+        self.request(|reply| Command::Read(Box::new(move |state| {
+            reply.send(read(state)).ok();
+        }))).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+    use crate::orderbook::database::Market;
+    use crate::orderbook::matcher::NewOrder;
+    use crate::orderbook::model::{OrderType, SnapshotPublished, TimeInForce};
+    use crate::orderbook::types::big_uint::MyBigUint;
+
+    #[tokio::test]
+    async fn rejected_commands_publish_no_snapshot() {
+        let market = Market::in_memory("TEST");
+        let _snapshots = market.broker.subscribe::<SnapshotPublished>(&market.symbol);
+        let price = MyBigUint(BigUint::from(100u32));
+        let fok = NewOrder::limit(OrderType::Buy, price.clone(), 1).time_in_force(TimeInForce::Fok, None);
+        assert!(market.submit(fok).await.is_err());
+        assert!(market.cancel(OrderType::Buy, 42).await.is_err());
+        assert!(market.cancel_stop(42).await.is_err());
+        market.submit(NewOrder::limit(OrderType::Buy, price, 1)).await.unwrap();
+        // answered after the batch before it is done
+        market.read(|_| ()).await.unwrap();
+        assert_eq!(market.broker.lag()[0].queued, 1);
+    }
+}
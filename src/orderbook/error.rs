@@ -0,0 +1,74 @@
+use std::fmt;
+use std::fmt::Formatter;
+use async_graphql::{Error, ErrorExtensions};
+use crate::orderbook::model::OrderType;
+use crate::orderbook::types::big_uint::MyBigUint;
+
+#[derive(Debug, Clone)]
+pub(crate) enum OrderBookError {
+    UnknownMarket { symbol: String },
+    /// the market's engine thread is no longer running
+    MarketClosed { symbol: String },
+    UnknownOrder { id: usize, kind: OrderType },
+    UnknownStopOrder { id: usize },
+    /// fill-or-kill order that the book cannot fill completely
+    NotFillable { requested: usize, available: usize },
+    InvalidExpiry(&'static str),
+    InvalidDisplayQuantity,
+    /// zero, or more than an order may have
+    InvalidQuantity { max: usize },
+    /// a price of zero
+    InvalidPrice,
+    /// post-only order that would take liquidity
+    WouldCrossSpread { price: MyBigUint, best: MyBigUint },
+    /// subscriber that let its queue fill up, under the disconnect policy
+    SlowConsumer { capacity: usize },
+}
+
+impl OrderBookError {
+    fn code(&self) -> &'static str {
+        match self {
+            OrderBookError::UnknownMarket { .. } => "UNKNOWN_MARKET",
+            OrderBookError::MarketClosed { .. } => "MARKET_CLOSED",
+            OrderBookError::UnknownOrder { .. } => "UNKNOWN_ORDER",
+            OrderBookError::UnknownStopOrder { .. } => "UNKNOWN_STOP_ORDER",
+            OrderBookError::NotFillable { .. } => "NOT_FILLABLE",
+            OrderBookError::InvalidExpiry(_) => "INVALID_EXPIRY",
+            OrderBookError::InvalidDisplayQuantity => "INVALID_DISPLAY_QUANTITY",
+            OrderBookError::InvalidQuantity { .. } => "INVALID_QUANTITY",
+            OrderBookError::InvalidPrice => "INVALID_PRICE",
+            OrderBookError::WouldCrossSpread { .. } => "WOULD_CROSS_SPREAD",
+            OrderBookError::SlowConsumer { .. } => "SLOW_CONSUMER",
+        }
+    }
+}
+
+impl fmt::Display for OrderBookError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderBookError::UnknownMarket { symbol } => write!(f, "No market {}", symbol),
+            OrderBookError::MarketClosed { symbol } => write!(f, "Market {} is not accepting commands", symbol),
+            OrderBookError::UnknownOrder { id, kind } => write!(f, "No {} order with id {} in the book", kind, id),
+            OrderBookError::UnknownStopOrder { id } => write!(f, "No stop order with id {} waiting for its trigger", id),
+            OrderBookError::NotFillable { requested, available } => write!(f, "Fill-or-kill order for {} can only be filled for {}", requested, available),
+            OrderBookError::InvalidExpiry(reason) => write!(f, "Invalid expiry: {}", reason),
+            OrderBookError::InvalidDisplayQuantity => write!(f, "Display quantity must be positive"),
+            OrderBookError::InvalidQuantity { max } => write!(f, "Quantity must be between 1 and {}", max),
+            OrderBookError::InvalidPrice => write!(f, "Price must be positive"),
+            OrderBookError::WouldCrossSpread { price, best } => write!(f, "Post-only order at {} would cross the best opposite price {}", price, best),
+            OrderBookError::SlowConsumer { capacity } => write!(f, "Disconnected: more than {} messages were waiting for this subscription", capacity),
+        }
+    }
+}
+
+impl ErrorExtensions for OrderBookError {
+    fn extend(&self) -> Error {
+        Error::new(self.to_string()).extend_with(|_, e| {
+            e.set("code", self.code());
+            if let OrderBookError::WouldCrossSpread { price, best } = self {
+                e.set("price", price.to_string());
+                e.set("bestOppositePrice", best.to_string());
+            }
+        })
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use crate::orderbook::database::{Market, MarketState};
+use crate::orderbook::error::OrderBookError;
+use crate::orderbook::journal::JournalConfig;
+use crate::orderbook::recovery::recover;
+use crate::orderbook::retention::Retention;
+use crate::orderbook::simple_broker::SimpleBroker;
+
+/// The trading engine: all markets and the broker their events go through.
+/// Resolvers get it from the schema data, so independent exchanges can live in one process.
+pub struct Exchange {
+    markets: Mutex<HashMap<String, Arc<Market>>>,
+    pub(crate) broker: SimpleBroker,
+    /// given to every market
+    retention: Retention,
+    /// none to keep everything in memory only
+    pub(crate) journal: Option<JournalConfig>,
+}
+
+impl Exchange {
+    pub fn new(symbols: &[&str], broker: SimpleBroker, retention: Retention, journal: Option<JournalConfig>) -> Self {
+        let exchange = Exchange {
+            markets: Mutex::new(HashMap::new()),
+            broker,
+            retention,
+            journal,
+        };
+        symbols.iter().for_each(|symbol| {
+            exchange.register_market(symbol);
+        });
+        exchange
+    }
+
+    /// Get or create the market for `symbol`, recovering it from its journal if there is one
+    pub(crate) fn register_market(&self, symbol: &str) -> Arc<Market> {
+        self.markets.lock().unwrap()
+            .entry(symbol.to_string())
+            .or_insert_with(|| match &self.journal {
+                Some(config) => {
+                    let (state, journal) = recover(config, symbol, &self.retention)
+                        .unwrap_or_else(|e| panic!("cannot recover market {}: {}", symbol, e));
+                    Market::new(symbol, self.broker.clone(), self.retention.clone(), state, Some(journal))
+                }
+                None => Market::new(symbol, self.broker.clone(), self.retention.clone(), MarketState::new(), None),
+            })
+            .clone()
+    }
+
+    pub(crate) fn market(&self, symbol: &str) -> Result<Arc<Market>, OrderBookError> {
+        self.markets.lock().unwrap().get(symbol).cloned().ok_or_else(|| OrderBookError::UnknownMarket { symbol: symbol.to_string() })
+    }
+
+    /// All markets, sorted by symbol
+    pub(crate) fn markets(&self) -> Vec<Arc<Market>> {
+        let mut markets = self.markets.lock().unwrap().values().cloned().collect::<Vec<Arc<Market>>>();
+        markets.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+        markets
+    }
+}
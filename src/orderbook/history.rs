@@ -0,0 +1,56 @@
+use std::collections::VecDeque;
+use async_graphql::connection::{Connection, Edge};
+use async_graphql::InputObject;
+use chrono::FixedOffset;
+use crate::orderbook::model::{Deal, OrderType};
+use crate::orderbook::types::big_uint::MyBigUint;
+use crate::orderbook::types::date_time::MyDateTime;
+
+/// Deals to list; every bound is inclusive except `to`
+#[derive(Clone, Default, InputObject)]
+pub(crate) struct DealFilter {
+    pub(crate) from: Option<MyDateTime<FixedOffset>>,
+    pub(crate) to: Option<MyDateTime<FixedOffset>>,
+    pub(crate) kind: Option<OrderType>,
+    pub(crate) min_price: Option<MyBigUint>,
+    pub(crate) max_price: Option<MyBigUint>,
+    pub(crate) min_quantity: Option<usize>,
+    pub(crate) max_quantity: Option<usize>,
+}
+
+impl DealFilter {
+    fn matches(&self, d: &Deal) -> bool {
+        self.from.as_ref().is_none_or(|from| d.created_at.0 >= from.0)
+            && self.to.as_ref().is_none_or(|to| d.created_at.0 < to.0)
+            && self.kind.is_none_or(|kind| d.kind == kind)
+            && self.min_price.as_ref().is_none_or(|min_price| d.price >= *min_price)
+            && self.max_price.as_ref().is_none_or(|max_price| d.price <= *max_price)
+            && self.min_quantity.is_none_or(|min_quantity| d.quantity >= min_quantity)
+            && self.max_quantity.is_none_or(|max_quantity| d.quantity <= max_quantity)
+    }
+}
+
+/// One page of the deals matching `filter`, newest first; cursors are deal sequence numbers,
+/// so `after` goes back in time and `before` forward
+pub(crate) fn page(
+    deals: &VecDeque<Deal>,
+    filter: &DealFilter,
+    after: Option<usize>,
+    before: Option<usize>,
+    first: Option<usize>,
+    last: Option<usize>,
+) -> Connection<usize, Deal> {
+    let matching = deals.iter().filter(|d| filter.matches(d)).collect::<Vec<&Deal>>();
+    let mut start = after.map_or(0, |after| matching.partition_point(|d| d.seq >= after));
+    let mut end = before.map_or(matching.len(), |before| matching.partition_point(|d| d.seq > before));
+    end = end.max(start);
+    if let Some(first) = first {
+        end = end.min(start + first);
+    }
+    if let Some(last) = last {
+        start = start.max(end.saturating_sub(last));
+    }
+    let mut connection = Connection::new(start > 0, end < matching.len());
+    connection.append(matching[start..end].iter().map(|d| Edge::new(d.seq, (*d).clone())));
+    connection
+}
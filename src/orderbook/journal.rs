@@ -0,0 +1,225 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use chrono::FixedOffset;
+use serde::{Deserialize, Serialize};
+use crate::orderbook::matcher::{NewOrder, now};
+use crate::orderbook::model::{BookUpdate, OrderCommons, OrderType};
+use crate::orderbook::stop_book::StopOrder;
+use crate::orderbook::types::date_time::MyDateTime;
+
+/// When journal writes are forced to disk
+#[derive(Clone, Copy, Debug)]
+pub enum FsyncPolicy {
+    /// after every batch of commands, before any of them is answered or any of their events published
+    Always,
+    /// at most once per period, so a crash loses at most that much
+    Interval(Duration),
+    /// left to the OS
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => s.parse::<u64>()
+                .map(|ms| FsyncPolicy::Interval(Duration::from_millis(ms)))
+                .map_err(|_| format!("unknown fsync policy {}, expected always, never or a period in milliseconds", s)),
+        }
+    }
+}
+
+/// Where the markets keep their journals and snapshots, one of each per market
+#[derive(Clone)]
+pub struct JournalConfig {
+    pub dir: PathBuf,
+    pub fsync: FsyncPolicy,
+    /// set the existing files aside instead of recovering from them
+    pub fresh: bool,
+}
+
+impl JournalConfig {
+    pub(crate) fn path(&self, symbol: &str, extension: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", symbol, extension))
+    }
+}
+
+/// A point in a journal: the last record before it and the byte offset right after that record
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub(crate) struct JournalPosition {
+    pub(crate) seq: u64,
+    pub(crate) offset: u64,
+}
+
+/// What a market's engine did. Book updates, stop placements and cancellations are enough to rebuild the book
+/// and the deal history; the other entries record the commands behind them and follow the updates they caused
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub(crate) enum JournalEntry {
+    /// a submitted order the matcher took, with the id of the part left resting
+    OrderAccepted { order: NewOrder, id: Option<usize> },
+    OrderCancelled { kind: OrderType, id: usize },
+    OrderModified { kind: OrderType, id: usize, data: OrderCommons, new_id: Option<usize> },
+    /// good-till-date orders removed past their expiry, by the expiry poll or before an order is matched
+    OrdersExpired { ids: Vec<usize> },
+    StopPlaced { order: StopOrder },
+    StopCancelled { id: usize },
+    StopTriggered { order: StopOrder },
+    /// an order added, filled or removed, or a deal, with its book update sequence
+    Book(BookUpdate),
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct JournalRecord {
+    /// position in the market's journal, counting from 1 without gaps
+    pub(crate) seq: u64,
+    pub(crate) time: MyDateTime<FixedOffset>,
+    pub(crate) entry: JournalEntry,
+}
+
+/// Append-only log of a market's engine: each record is a little-endian u32 length and u32 CRC-32 of its JSON, then the JSON.
+/// The engine appends records while it applies a batch and commits them before answering the batch's commands
+pub(crate) struct Journal {
+    file: File,
+    fsync: FsyncPolicy,
+    next_seq: u64,
+    /// end of the records committed to the file
+    written: JournalPosition,
+    /// records appended since the last commit
+    pending: Vec<u8>,
+    /// written but not yet synced
+    unsynced: bool,
+    last_sync: Instant,
+}
+
+impl Journal {
+    /// Open the journal of `symbol` to continue it, returning the records after `from`; a record cut short by a crash is dropped
+    pub(crate) fn open(config: &JournalConfig, symbol: &str, from: JournalPosition) -> io::Result<(Self, Vec<JournalRecord>)> {
+        fs::create_dir_all(&config.dir)?;
+        let path = config.path(symbol, "journal");
+        let (records, end) = read_records(&path, from)?;
+        let mut file = OpenOptions::new().create(true).write(true).truncate(false).open(&path)?;
+        file.set_len(end)?;
+        file.seek(SeekFrom::End(0))?;
+        let last_seq = records.last().map_or(from.seq, |record| record.seq);
+        let journal = Journal {
+            file,
+            fsync: config.fsync,
+            next_seq: last_seq + 1,
+            written: JournalPosition { seq: last_seq, offset: end },
+            pending: Vec::new(),
+            unsynced: false,
+            last_sync: Instant::now(),
+        };
+        Ok((journal, records))
+    }
+
+    pub(crate) fn append(&mut self, entry: JournalEntry) {
+        let record = JournalRecord { seq: self.next_seq, time: MyDateTime(now()), entry };
+        self.next_seq += 1;
+        let payload = serde_json::to_vec(&record).expect("journal records are plain data");
+        assert!(payload.len() <= MAX_RECORD, "journal record {} is {} bytes long", record.seq, payload.len());
+        self.pending.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        self.pending.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        self.pending.extend_from_slice(&payload);
+    }
+
+    /// Write the appended records and sync them if the policy says it is time
+    pub(crate) fn commit(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            self.file.write_all(&self.pending)?;
+            self.written = JournalPosition { seq: self.next_seq - 1, offset: self.written.offset + self.pending.len() as u64 };
+            self.pending.clear();
+            self.unsynced = true;
+        }
+        let due = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Interval(period) => self.last_sync.elapsed() >= period,
+            FsyncPolicy::Never => false,
+        };
+        if due {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Force the committed records to disk whatever the policy, returning where they end; a snapshot may point no further
+    pub(crate) fn sync(&mut self) -> io::Result<JournalPosition> {
+        if self.unsynced {
+            self.file.sync_data()?;
+            self.unsynced = false;
+            self.last_sync = Instant::now();
+        }
+        Ok(self.written)
+    }
+}
+
+/// Length and checksum of a record
+const HEADER: usize = 8;
+/// the longest record a journal holds, so that a garbage length is seen as such
+const MAX_RECORD: usize = 16 << 20;
+
+/// The JSON of the record at the start of `bytes`, none if the record is cut short, its length is out of bounds
+/// or its checksum does not match
+fn payload(bytes: &[u8]) -> Option<&[u8]> {
+    let header = bytes.get(..HEADER)?;
+    let length = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+    if length == 0 || length > MAX_RECORD {
+        return None;
+    }
+    bytes.get(HEADER..HEADER + length).filter(|payload| crc32fast::hash(payload) == checksum)
+}
+
+/// The complete records of a journal file after `from`, and the offset where they end; a missing file is an empty journal.
+/// A bad record is the tail a crash left if no sound record follows it, and it is dropped with whatever comes after it
+fn read_records(path: &Path, from: JournalPosition) -> io::Result<(Vec<JournalRecord>, u64)> {
+    let invalid = |message: String| io::Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), message));
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound && from.offset == 0 => return Ok((Vec::new(), 0)),
+        Err(e) => return Err(e),
+    };
+    if file.metadata()?.len() < from.offset {
+        return Err(invalid(format!("shorter than the {} bytes already covered", from.offset)));
+    }
+    file.seek(SeekFrom::Start(from.offset))?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    let mut records = Vec::<JournalRecord>::new();
+    let mut at = 0;
+    while at < bytes.len() {
+        let record = payload(&bytes[at..])
+            .and_then(|payload| serde_json::from_slice::<JournalRecord>(payload).ok().map(|record| (record, payload.len())));
+        let (record, length) = match record {
+            Some(record) => record,
+            None if followed_by_record(&bytes[at..]) => {
+                return Err(invalid(format!("bad record at byte {} with sound records after it", from.offset + at as u64)));
+            }
+            None => break,
+        };
+        let expected = records.last().map_or(from.seq, |last| last.seq) + 1;
+        if record.seq != expected {
+            return Err(invalid(format!("record {} at byte {} where {} was expected", record.seq, from.offset + at as u64, expected)));
+        }
+        records.push(record);
+        at += HEADER + length;
+    }
+    Ok((records, from.offset + at as u64))
+}
+
+/// Whether a sound record follows the bad one at the start of `bytes`, by the length in its header
+fn followed_by_record(bytes: &[u8]) -> bool {
+    let length = bytes.get(..4).map(|length| u32::from_le_bytes(length.try_into().unwrap()) as usize);
+    length
+        .filter(|length| (1..=MAX_RECORD).contains(length))
+        .and_then(|length| bytes.get(HEADER + length..))
+        .and_then(payload)
+        .is_some()
+}
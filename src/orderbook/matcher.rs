@@ -1,83 +1,408 @@
-use std::collections::BinaryHeap;
-use crate::orderbook::database::ORDERBOOK_STATE;
-use async_graphql::*;
-use slab::Slab;
-use std::fmt::Display;
-use uuid::Uuid;
-use crate::orderbook::model::{Deal, deal, Order, OrderCommons, OrderType, publish_order_add, publish_order_remove};
+use std::cmp::min;
+use chrono::{FixedOffset, Utc};
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use crate::orderbook::database::{Market, MarketState};
+use crate::orderbook::error::OrderBookError;
+use crate::orderbook::journal::JournalEntry;
+use crate::orderbook::model::{Deal, deal, Order, OrderBook, OrderCommons, OrderType, PostOnly, publish_order_add, publish_order_remove, publish_order_update, publish_stop_triggered, TimeInForce};
+use crate::orderbook::stop_book::StopOrder;
 use crate::orderbook::types::big_uint::MyBigUint;
+use crate::orderbook::types::date_time::MyDateTime;
 
 pub(crate) struct Matcher {
 
 }
 
-struct CompareOrders {
-    f: Box<dyn Fn(&MyBigUint, &MyBigUint) -> bool>,
+/// Minimal price increment
+const TICK: u32 = 1;
+
+/// An order as submitted, before it is matched
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct NewOrder {
+    pub(crate) kind: OrderType,
+    pub(crate) quantity: usize,
+    /// none for market orders
+    pub(crate) limit: Option<MyBigUint>,
+    pub(crate) time_in_force: TimeInForce,
+    /// only for good-till-date orders
+    pub(crate) expires_at: Option<MyDateTime<FixedOffset>>,
+    /// maker-only: what to do if the order would take liquidity
+    pub(crate) post_only: Option<PostOnly>,
+    /// iceberg: how much of the resting quantity is shown at a time
+    pub(crate) display_quantity: Option<usize>,
 }
 
-impl CompareOrders {
-    fn new<F>(f: F) -> CompareOrders
-        where
-            F: Fn(&MyBigUint, &MyBigUint) -> bool + 'static,
-    {
-        CompareOrders { f: Box::new(f) }
+impl NewOrder {
+    pub(crate) fn limit(kind: OrderType, price: MyBigUint, quantity: usize) -> Self {
+        NewOrder {
+            kind,
+            quantity,
+            limit: Some(price),
+            time_in_force: TimeInForce::Gtc,
+            expires_at: None,
+            post_only: None,
+            display_quantity: None,
+        }
+    }
+
+    /// Sweep the opposite side until filled or the side is empty; a market order never rests
+    pub(crate) fn market(kind: OrderType, quantity: usize) -> Self {
+        NewOrder {
+            kind,
+            quantity,
+            limit: None,
+            time_in_force: TimeInForce::Ioc,
+            expires_at: None,
+            post_only: None,
+            display_quantity: None,
+        }
+    }
+
+    pub(crate) fn time_in_force(self, time_in_force: TimeInForce, expires_at: Option<MyDateTime<FixedOffset>>) -> Self {
+        NewOrder { time_in_force, expires_at, ..self }
+    }
+
+    pub(crate) fn post_only(self, post_only: Option<PostOnly>) -> Self {
+        NewOrder { post_only, ..self }
+    }
+
+    pub(crate) fn iceberg(self, display_quantity: Option<usize>) -> Self {
+        NewOrder { display_quantity, ..self }
+    }
+
+    fn validate(&self) -> Result<(), OrderBookError> {
+        if self.display_quantity == Some(0) {
+            return Err(OrderBookError::InvalidDisplayQuantity);
+        }
+        match (self.time_in_force, &self.expires_at) {
+            (TimeInForce::Gtd, None) => Err(OrderBookError::InvalidExpiry("good-till-date orders need an expiry")),
+            (TimeInForce::Gtd, Some(expires_at)) if expires_at.0 <= now() => Err(OrderBookError::InvalidExpiry("expiry is in the past")),
+            (TimeInForce::Gtd, Some(_)) => Ok(()),
+            (_, Some(_)) => Err(OrderBookError::InvalidExpiry("only good-till-date orders expire")),
+            (_, None) => Ok(()),
+        }
     }
 }
 
-struct DealPrice {
-    f: Box<dyn Fn(&OrderCommons, &OrderCommons) -> MyBigUint>,
+pub(crate) fn now() -> chrono::DateTime<FixedOffset> {
+    Utc::now().with_timezone(&FixedOffset::east(0))
 }
 
-impl DealPrice {
-    fn new<F>(f: F) -> DealPrice
-        where
-            F: Fn(&OrderCommons, &OrderCommons) -> MyBigUint + 'static,
-    {
-        DealPrice { f: Box::new(f) }
-    }
+/// Outcome of feeding one order into the book
+pub(crate) struct MatchResult {
+    /// the part of the incoming order left resting in the book, if any
+    pub(crate) order: Option<Order>,
+    pub(crate) deals: Vec<Deal>,
+    /// quantity that was neither filled nor left resting
+    pub(crate) unfilled_quantity: usize,
 }
 
+/// Only the market's engine thread calls into the matcher, so `state` is never shared
 impl Matcher {
-    pub(crate) fn run(kind: OrderType, data: &OrderCommons) {
-        let state = &mut ORDERBOOK_STATE.lock().unwrap().orderbook;
-        let (retrieve_queue, add_queue, retrieve_map, add_map, comparison, deal_price) = match kind {
-            OrderType::Buy => (&mut state.asks, &mut state.bids, &mut state.ask_map, &mut state.bid_map, CompareOrders::new(|p1: &MyBigUint, p2: &MyBigUint| p1.0 <= p2.0), DealPrice::new(|new_order: &OrderCommons, retrieved_order: &OrderCommons| retrieved_order.price.clone())),
-            OrderType::Sell => (&mut state.bids, &mut state.asks, &mut state.bid_map, &mut state.ask_map, CompareOrders::new(|p1: &MyBigUint, p2: &MyBigUint| p1.0 >= p2.0), DealPrice::new(|new_order: &OrderCommons, retrieved_order: &OrderCommons| new_order.price.clone()))
+    pub(crate) fn submit(market: &Market, state: &mut MarketState, order: &NewOrder) -> Result<MatchResult, OrderBookError> {
+        let result = Matcher::place(market, state, order);
+        Matcher::run_triggered(market, state);
+        result
+    }
+
+    /// Remove a resting order from the book
+    pub(crate) fn cancel(market: &Market, state: &mut MarketState, kind: OrderType, id: usize) -> Result<Order, OrderBookError> {
+        Matcher::remove(market, state, kind, id)
+    }
+
+    /// Park a stop order in the trigger book; `limit_price` makes it a stop-limit order
+    pub(crate) fn submit_stop(market: &Market, state: &mut MarketState, kind: OrderType, trigger_price: MyBigUint, limit_price: Option<MyBigUint>, quantity: usize) -> StopOrder {
+        let book = &mut state.orderbook;
+        let id = book.next_id;
+        book.next_id += 1;
+        let seq = book.next_seq;
+        book.next_seq += 1;
+        let order = StopOrder { id, kind, trigger_price, limit_price, quantity, seq };
+        market.journal(|| JournalEntry::StopPlaced { order: order.clone() });
+        book.stops.insert(order.clone());
+        Matcher::run_triggered(market, state);
+        order
+    }
+
+    pub(crate) fn cancel_stop(market: &Market, state: &mut MarketState, id: usize) -> Result<StopOrder, OrderBookError> {
+        let order = state.orderbook.stops.remove(id).ok_or(OrderBookError::UnknownStopOrder { id })?;
+        market.journal(|| JournalEntry::StopCancelled { id });
+        Ok(order)
+    }
+
+    /// Cancel-replace: the amended order is matched again and loses its time priority, keeping its time in force,
+    /// post-only mode and iceberg slice
+    pub(crate) fn modify(market: &Market, state: &mut MarketState, kind: OrderType, id: usize, data: &OrderCommons) -> Result<MatchResult, OrderBookError> {
+        let existing = state.orderbook.side(kind).get(id).ok_or(OrderBookError::UnknownOrder { id, kind })?;
+        let time_in_force = if existing.expires_at.is_some() { TimeInForce::Gtd } else { TimeInForce::Gtc };
+        let replacement = NewOrder::limit(kind, data.price.clone(), data.quantity)
+            .time_in_force(time_in_force, existing.expires_at.clone())
+            .post_only(existing.post_only)
+            .iceberg(existing.display_quantity);
+        replacement.validate()?;
+        // a rejected amendment leaves the order as it was; the check only looks at the opposite side
+        Matcher::post_only_price(&state.orderbook, &replacement)?;
+        Matcher::remove(market, state, kind, id)?;
+        let result = Matcher::place(market, state, &replacement);
+        Matcher::run_triggered(market, state);
+        result
+    }
+
+    /// Drop good-till-date orders whose expiry has passed
+    pub(crate) fn expire(market: &Market, state: &mut MarketState) -> Vec<Order> {
+        let expired = state.orderbook.remove_expired(&now());
+        expired.iter().for_each(|order| publish_order_remove(market, &mut state.sequence, order));
+        if !expired.is_empty() {
+            market.journal(|| JournalEntry::OrdersExpired { ids: expired.iter().map(|order| order.id).collect() });
+        }
+        expired
+    }
+
+    /// Feed stop orders triggered by trades into the book until no more fire
+    fn run_triggered(market: &Market, state: &mut MarketState) {
+        while let Some((stop, trade_price)) = state.orderbook.stops.next_pending() {
+            publish_stop_triggered(market, &stop, &trade_price);
+            let order = match &stop.limit_price {
+                Some(limit_price) => NewOrder::limit(stop.kind, limit_price.clone(), stop.quantity),
+                None => NewOrder::market(stop.kind, stop.quantity),
+            };
+            Matcher::place(market, state, &order).ok();
+        }
+    }
+
+    fn remove(market: &Market, state: &mut MarketState, kind: OrderType, id: usize) -> Result<Order, OrderBookError> {
+        let order = state.orderbook.remove_order(kind, id).ok_or(OrderBookError::UnknownOrder { id, kind })?;
+        publish_order_remove(market, &mut state.sequence, &order);
+        Ok(order)
+    }
+
+    /// For a post-only order that would cross the spread: reject it, or give the price one tick
+    /// behind the best opposite price to use instead
+    fn post_only_price(state: &OrderBook, new_order: &NewOrder) -> Result<Option<MyBigUint>, OrderBookError> {
+        let (mode, price) = match (new_order.post_only, &new_order.limit) {
+            (Some(mode), Some(price)) => (mode, price),
+            // market orders cannot be post-only
+            _ => return Ok(None),
+        };
+        let opposite_side = state.side(new_order.kind.opposite());
+        let best = match opposite_side.best_price() {
+            Some(best) if opposite_side.crosses(best, Some(price)) => best,
+            _ => return Ok(None),
         };
-        // recursive
-        fn _run(qty: usize, kind: OrderType, data: &OrderCommons, retrieve_queue: &mut BinaryHeap<Order>, add_queue: &mut BinaryHeap<Order>, retrieve_map: &mut Slab<OrderCommons>, add_map: &mut Slab<OrderCommons>, comparison: &CompareOrders, deal_price: &DealPrice) {
-            fn create_order(d: &OrderCommons, kind: OrderType, add_queue: &mut BinaryHeap<Order>, add_map: &mut Slab<OrderCommons>) -> Order {
-                let id = add_map.insert(d.clone());
-                let order = Order { id, data: d.clone(), kind };
-                add_queue.push(order.clone());
-                publish_order_add(&order);
-                return order.clone();
+        let reject = || OrderBookError::WouldCrossSpread { price: price.clone(), best: best.clone() };
+        match (mode, new_order.kind) {
+            (PostOnly::Reject, _) => Err(reject()),
+            (PostOnly::Reprice, OrderType::Buy) if best.0 > BigUint::from(TICK) => Ok(Some(MyBigUint(&best.0 - TICK))),
+            (PostOnly::Reprice, OrderType::Buy) => Err(reject()),
+            (PostOnly::Reprice, OrderType::Sell) => Ok(Some(MyBigUint(&best.0 + TICK))),
+        }
+    }
+
+    fn place(market: &Market, state: &mut MarketState, new_order: &NewOrder) -> Result<MatchResult, OrderBookError> {
+        new_order.validate()?;
+        // the expiry poll only runs every second, nothing trades against an order past its expiry in between
+        Matcher::expire(market, state);
+        let kind = new_order.kind;
+        let repriced = Matcher::post_only_price(&state.orderbook, new_order)?;
+        let limit = repriced.as_ref().or(new_order.limit.as_ref());
+        let MarketState { orderbook: OrderBook { bids, asks, stops, next_id, next_seq }, history, sequence } = state;
+        let (opposite_side, own_side) = match kind {
+            OrderType::Buy => (asks, bids),
+            OrderType::Sell => (bids, asks),
+        };
+        if new_order.time_in_force == TimeInForce::Fok {
+            // all or nothing: check before touching the book
+            let available = opposite_side.fillable_quantity(limit, new_order.quantity);
+            if available < new_order.quantity {
+                return Err(OrderBookError::NotFillable { requested: new_order.quantity, available });
+            }
+        }
+        let mut qty = new_order.quantity;
+        let mut deals = Vec::new();
+        while qty > 0 {
+            let resting_order = match opposite_side.best_order() {
+                Some(best_order) if opposite_side.crosses(&best_order.data.price, limit) => best_order,
+                _ => break,
             };
-            let peeked_order = retrieve_queue.peek();
-            if peeked_order.is_some() && (comparison.f)(&peeked_order.unwrap().data.price, &data.price) {
-                let retrieved_order = retrieve_queue.pop().unwrap(); // is_some already
-                retrieve_map.remove(retrieved_order.id);
-                publish_order_remove(&retrieved_order);
-                let retrieved_qty = retrieved_order.data.quantity.clone();
-                let diff = qty as i32 - retrieved_qty as i32;
-                deal(Deal::new((deal_price.f)(&data, &retrieved_order.data), retrieved_qty, kind));
-                if diff == 0 {
-                    // deal done
-                } else if diff > 0 {
-                    _run(diff as usize, kind, &data, retrieve_queue, add_queue, retrieve_map, add_map, &comparison, &deal_price);
-                } else if diff < 0 {
-                    // TODO just update the order
-                    create_order(&OrderCommons {
-                        price: retrieved_order.data.price.clone(),
-                        quantity: diff.abs() as usize,
-                    }, kind, add_queue, add_map);
-                }
+            let (resting_id, resting_qty, resting_hidden) = (resting_order.id, resting_order.data.quantity, resting_order.hidden_quantity);
+            let filled = min(qty, resting_qty);
+            // the resting order sets the price, whichever side comes in
+            let d = deal(market, history, sequence, Deal::new(&market.symbol, resting_order.data.price.clone(), filled, kind));
+            stops.record_trade(&d.price);
+            deals.push(d);
+            qty -= filled;
+            if resting_qty > filled {
+                // partial fill: the resting order keeps its id, side and place in the queue
+                let updated_order = opposite_side.set_quantity(resting_id, resting_qty - filled).unwrap();
+                publish_order_update(market, sequence, updated_order);
+            } else if resting_hidden > 0 {
+                // iceberg: the next slice loses time priority
+                let seq = *next_seq;
+                *next_seq += 1;
+                let replenished_order = opposite_side.replenish(resting_id, seq).unwrap();
+                publish_order_update(market, sequence, replenished_order);
             } else {
-
-                let id = create_order(&data, kind, add_queue, add_map);
-                // publish_order(id);
+                let retrieved_order = opposite_side.remove(resting_id).unwrap();
+                publish_order_remove(market, sequence, &retrieved_order);
             }
+        }
+        let price = match (limit, new_order.time_in_force) {
+            (Some(price), TimeInForce::Gtc | TimeInForce::Gtd) if qty > 0 => price,
+            // filled, or the remainder is cancelled
+            _ => return Ok(MatchResult { order: None, deals, unfilled_quantity: qty }),
         };
-        _run(data.quantity, kind, &data, retrieve_queue, add_queue, retrieve_map, add_map, &comparison, &deal_price);
+        let id = *next_id;
+        *next_id += 1;
+        // arrival sequence breaks ties between orders at the same price
+        let seq = *next_seq;
+        *next_seq += 1;
+        let visible = new_order.display_quantity.map_or(qty, |display_quantity| min(display_quantity, qty));
+        let order = Order {
+            id,
+            data: OrderCommons {
+                quantity: visible,
+                price: price.clone(),
+            },
+            kind,
+            seq,
+            expires_at: new_order.expires_at.clone(),
+            display_quantity: new_order.display_quantity,
+            hidden_quantity: qty - visible,
+            post_only: new_order.post_only,
+        };
+        own_side.insert(order.clone());
+        publish_order_add(market, sequence, &order);
+        Ok(MatchResult { order: Some(order), deals, unfilled_quantity: 0 })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use futures_util::StreamExt;
+    use num_bigint::BigUint;
+    use crate::orderbook::database::Market;
+    use crate::orderbook::error::OrderBookError;
+    use crate::orderbook::matcher::{NewOrder, now};
+    use crate::orderbook::model::{OrderCommons, OrderType, PostOnly, StopTriggered, TimeInForce};
+    use crate::orderbook::types::big_uint::MyBigUint;
+    use crate::orderbook::types::date_time::MyDateTime;
+
+    fn price(p: u32) -> MyBigUint {
+        MyBigUint(BigUint::from(p))
+    }
+
+    /// Resting asks as (id, visible quantity), in matching order
+    async fn asks(market: &Market) -> Vec<(usize, usize)> {
+        market.read(|state| state.orderbook.asks.orders().map(|order| (order.id, order.data.quantity)).collect()).await.unwrap()
+    }
+
+    async fn rest(market: &Market, order: NewOrder) -> usize {
+        market.submit(order).await.unwrap().order.unwrap().id
+    }
+
+    #[tokio::test]
+    async fn same_price_fills_in_arrival_order() {
+        let market = Market::in_memory("TEST");
+        let first = rest(&market, NewOrder::limit(OrderType::Sell, price(100), 10)).await;
+        let second = rest(&market, NewOrder::limit(OrderType::Sell, price(100), 10)).await;
+        let third = rest(&market, NewOrder::limit(OrderType::Sell, price(100), 10)).await;
+        assert_eq!(asks(&market).await, vec![(first, 10), (second, 10), (third, 10)]);
+        let result = market.submit(NewOrder::limit(OrderType::Buy, price(100), 15)).await.unwrap();
+        assert_eq!(result.deals.iter().map(|d| d.quantity).collect::<Vec<_>>(), vec![10, 5]);
+        assert_eq!(asks(&market).await, vec![(second, 5), (third, 10)]);
+        market.submit(NewOrder::limit(OrderType::Buy, price(100), 5)).await.unwrap();
+        assert_eq!(asks(&market).await, vec![(third, 10)]);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn better_price_fills_before_earlier_order() {
+        let market = Market::in_memory("TEST");
+        let worse = rest(&market, NewOrder::limit(OrderType::Sell, price(101), 10)).await;
+        let better = rest(&market, NewOrder::limit(OrderType::Sell, price(100), 10)).await;
+        assert_eq!(asks(&market).await, vec![(better, 10), (worse, 10)]);
+        market.submit(NewOrder::limit(OrderType::Buy, price(101), 10)).await.unwrap();
+        assert_eq!(asks(&market).await, vec![(worse, 10)]);
+    }
+
+    #[tokio::test]
+    async fn partial_fill_keeps_its_place() {
+        let market = Market::in_memory("TEST");
+        let first = rest(&market, NewOrder::limit(OrderType::Sell, price(100), 10)).await;
+        let second = rest(&market, NewOrder::limit(OrderType::Sell, price(100), 10)).await;
+        market.submit(NewOrder::limit(OrderType::Buy, price(100), 4)).await.unwrap();
+        assert_eq!(asks(&market).await, vec![(first, 6), (second, 10)]);
+        let result = market.submit(NewOrder::limit(OrderType::Buy, price(100), 7)).await.unwrap();
+        assert_eq!(result.deals.iter().map(|d| d.quantity).collect::<Vec<_>>(), vec![6, 1]);
+        assert_eq!(asks(&market).await, vec![(second, 9)]);
+    }
+
+    #[tokio::test]
+    async fn iceberg_slice_goes_to_the_back() {
+        let market = Market::in_memory("TEST");
+        let iceberg = rest(&market, NewOrder::limit(OrderType::Sell, price(100), 30).iceberg(Some(10))).await;
+        let plain = rest(&market, NewOrder::limit(OrderType::Sell, price(100), 10)).await;
+        assert_eq!(asks(&market).await, vec![(iceberg, 10), (plain, 10)]);
+        market.submit(NewOrder::limit(OrderType::Buy, price(100), 10)).await.unwrap();
+        assert_eq!(asks(&market).await, vec![(plain, 10), (iceberg, 10)]);
+        market.submit(NewOrder::limit(OrderType::Buy, price(100), 15)).await.unwrap();
+        assert_eq!(asks(&market).await, vec![(iceberg, 5)]);
+    }
+
+    #[tokio::test]
+    async fn deals_trade_at_the_resting_price() {
+        let market = Market::in_memory("TEST");
+        rest(&market, NewOrder::limit(OrderType::Buy, price(200), 10)).await;
+        rest(&market, NewOrder::limit(OrderType::Sell, price(300), 10)).await;
+        let sell = market.submit(NewOrder::limit(OrderType::Sell, price(1), 5)).await.unwrap();
+        let buy = market.submit(NewOrder::limit(OrderType::Buy, price(1000), 5)).await.unwrap();
+        assert_eq!(sell.deals[0].price, price(200));
+        assert_eq!(buy.deals[0].price, price(300));
+    }
+
+    #[tokio::test]
+    async fn amendments_keep_post_only() {
+        let market = Market::in_memory("TEST");
+        let ask = rest(&market, NewOrder::limit(OrderType::Sell, price(100), 10)).await;
+        let reject = rest(&market, NewOrder::limit(OrderType::Buy, price(90), 10).post_only(Some(PostOnly::Reject))).await;
+        let reprice = rest(&market, NewOrder::limit(OrderType::Buy, price(80), 10).post_only(Some(PostOnly::Reprice))).await;
+        let amended = market.modify(OrderType::Buy, reject, OrderCommons { quantity: 10, price: price(100) }).await;
+        assert!(matches!(amended, Err(OrderBookError::WouldCrossSpread { .. })));
+        let amended = market.modify(OrderType::Buy, reprice, OrderCommons { quantity: 10, price: price(100) }).await.unwrap();
+        assert!(amended.deals.is_empty());
+        assert_eq!(amended.order.unwrap().data.price, price(99));
+        let bids = market.read(|state| state.orderbook.bids.orders().map(|order| order.id).collect::<Vec<usize>>()).await.unwrap();
+        assert_eq!(bids.len(), 2);
+        assert!(bids.contains(&reject));
+        assert_eq!(asks(&market).await, vec![(ask, 10)]);
+    }
+
+    #[tokio::test]
+    async fn expired_orders_do_not_trade() {
+        let market = Market::in_memory("TEST");
+        let expires_at = MyDateTime(now() + Duration::milliseconds(20));
+        rest(&market, NewOrder::limit(OrderType::Sell, price(100), 10).time_in_force(TimeInForce::Gtd, Some(expires_at))).await;
+        let live = rest(&market, NewOrder::limit(OrderType::Sell, price(101), 10)).await;
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let result = market.submit(NewOrder::limit(OrderType::Buy, price(101), 5)).await.unwrap();
+        assert_eq!(result.deals.iter().map(|d| (d.price.clone(), d.quantity)).collect::<Vec<_>>(), vec![(price(101), 5)]);
+        assert_eq!(asks(&market).await, vec![(live, 5)]);
+    }
+
+    #[tokio::test]
+    async fn stops_report_the_trade_that_fired_them() {
+        let market = Market::in_memory("TEST");
+        let mut triggered = market.broker.subscribe::<StopTriggered>(&market.symbol);
+        for p in [100, 101, 102] {
+            rest(&market, NewOrder::limit(OrderType::Sell, price(p), 1)).await;
+        }
+        market.submit_stop(OrderType::Buy, price(100), None, 1).await.unwrap();
+        market.submit_stop(OrderType::Buy, price(101), None, 1).await.unwrap();
+        market.submit(NewOrder::limit(OrderType::Buy, price(102), 3)).await.unwrap();
+        let first = triggered.next().await.unwrap().unwrap();
+        let second = triggered.next().await.unwrap().unwrap();
+        assert_eq!((first.order.trigger_price, first.last_price), (price(100), price(100)));
+        assert_eq!((second.order.trigger_price, second.last_price), (price(101), price(101)));
+    }
+}
@@ -1,51 +1,102 @@
 mod model;
 mod reporter;
 mod database;
+mod depth;
+mod engine;
+mod exchange;
 mod matcher;
 mod types;
 mod simple_broker;
-
-use async_graphql::*;
-use std::cmp::Ordering;
-use std::collections::BinaryHeap;
-use std::cmp;
-use std::iter;
-use std::fmt;
+mod error;
+mod history;
+mod recovery;
+mod journal;
+mod retention;
+mod book_side;
+mod candles;
+mod conflate;
+mod stop_book;
+mod ticker;
 
 use tokio::time;
 
-use async_graphql::{EmptyMutation, EmptySubscription, Schema};
-use slab::Slab;
-use std::collections::HashMap;
-use std::fmt::{Display, Formatter};
-use std::iter::Rev;
-use std::ops::Add;
-use std::str::FromStr;
+use async_graphql::Schema;
+use std::sync::Arc;
 use std::time::Duration;
-use num_bigint::{BigUint, ParseBigIntError};
-use model::{OrderCommons, OrderType};
-use types::*;
+use futures_util::future;
+use model::OrderType;
+
+use crate::orderbook::database::Market;
+pub use crate::orderbook::exchange::Exchange;
+pub use crate::orderbook::journal::{FsyncPolicy, JournalConfig};
+pub use crate::orderbook::retention::{DealArchive, Retention};
+pub use crate::orderbook::simple_broker::{SimpleBroker, SlowConsumerPolicy};
+use crate::orderbook::matcher::NewOrder;
+use crate::orderbook::recovery::{MarketSnapshot, write_snapshot};
+pub(crate) use crate::orderbook::model::{MutationRoot, QueryRoot, SubscriptionRoot};
+use crate::orderbook::reporter::{BookTop, Reporter};
+
+pub(crate) type OrderBookSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
 
-// pub use model::QueryRoot;
-use crate::orderbook::database::ORDERBOOK_STATE;
-use crate::orderbook::matcher::Matcher;
-pub(crate) use crate::orderbook::model::{QueryRoot, SubscriptionRoot};
-use crate::orderbook::reporter::{OrderScaffold, Reporter};
+/// Feed mock orders into every market
+pub async fn run_reporter_poll(exchange: Arc<Exchange>, market_orders: bool) {
+    future::join_all(exchange.markets().into_iter().map(|market| run_market_reporter(market, market_orders))).await;
+}
 
-pub(crate) type OrderBookSchema = Schema<QueryRoot, EmptyMutation, SubscriptionRoot>;
+async fn run_market_reporter(market: Arc<Market>, market_orders: bool) {
+    let mut interval_sec = time::interval(Duration::from_secs(1));
+    let mut reporter = Reporter::new(market_orders);
+    loop {
+        interval_sec.tick().await;
+        let scaffolds = reporter.step(&BookTop::of(&market.snapshot().orderbook));
+        for (x, order_type) in scaffolds.bids.iter().map(|x| (x, OrderType::Buy)).chain(scaffolds.asks.iter().map(|x| (x, OrderType::Sell))) {
+            let order = if x.market {
+                NewOrder::market(order_type, x.quantity)
+            } else {
+                NewOrder::limit(order_type, x.price.clone(), x.quantity)
+            };
+            market.submit(order).await.ok();
+        }
+    }
+}
 
-pub async fn run_reporter_poll() {
+/// Remove good-till-date orders once their expiry passes
+pub async fn run_expiry_poll(exchange: Arc<Exchange>) {
     let mut interval_sec = time::interval(Duration::from_secs(1));
-    let mut reporter = Reporter::new();
     loop {
         interval_sec.tick().await;
-        let scaffolds = reporter.step();
-        scaffolds.bids.iter().map(|x| (x, OrderType::Buy)).chain(scaffolds.asks.iter().map(|x| (x, OrderType::Sell))).for_each(move |(x, order_type)| {
-            Matcher::run(order_type, &OrderCommons {
-                quantity: x.quantity,
-                price: x.price.clone(),
-            })
-        });
+        for market in exchange.markets() {
+            market.expire().await.ok();
+        }
     }
 }
 
+/// Save the state of every market now and then, so that recovery only replays the journal since the last save
+pub async fn run_snapshot_poll(exchange: Arc<Exchange>, period: Duration) {
+    let config = match &exchange.journal {
+        Some(config) => config.clone(),
+        None => return,
+    };
+    let mut interval = time::interval(period);
+    // the first tick is immediate, and there is nothing new to save yet
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        for market in exchange.markets() {
+            let position = market.clone();
+            // reads happen once their batch is committed, so that the state and the synced journal position agree
+            let snapshot = match market.read(move |state| position.sync_journal().map(|journal| journal.map(|journal| MarketSnapshot::of(state, journal)))).await {
+                Ok(Some(Ok(snapshot))) => snapshot,
+                Ok(Some(Err(e))) => {
+                    eprintln!("journal of {}: {}", market.symbol, e);
+                    continue;
+                }
+                _ => continue,
+            };
+            let (config, symbol) = (config.clone(), market.symbol.clone());
+            if let Ok(Err(e)) = tokio::task::spawn_blocking(move || write_snapshot(&config, &symbol, &snapshot)).await {
+                eprintln!("snapshot of {}: {}", market.symbol, e);
+            }
+        }
+    }
+}
@@ -1,30 +1,36 @@
 use super::types::big_uint::MyBigUint;
-use std::borrow::Cow;
-use std::collections::{BinaryHeap, VecDeque};
-use std::time::Duration;
-use async_graphql::{connection::{Connection, Edge, EmptyFields, query}, Context, ContextSelectionSet, Enum, FieldResult, Interface, Object, Positioned, ServerResult};
-use async_graphql::parser::types::Field;
-use async_graphql::registry::Registry;
+use std::sync::Arc;
+use async_graphql::{Context, Enum, FieldResult, Object};
+use async_graphql::connection::{self, Connection};
 use async_graphql::*;
-use async_graphql::futures_util::StreamExt;
 use chrono::{DateTime, FixedOffset, Utc};
 use futures_core::Stream;
-use tokio_stream::wrappers::IntervalStream;
+use futures_util::{future, stream, StreamExt};
+use num_traits::Zero;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use std::fmt;
 use std::fmt::Formatter;
-use std::cmp::Ordering;
-use slab::Slab;
-use crate::orderbook::database::{HISTORY_CAPACITY, HISTORY_STATE, ORDERBOOK_STATE};
-use crate::orderbook::simple_broker::SimpleBroker;
+use crate::orderbook::book_side::BookSide;
+use crate::orderbook::candles::{Candle, CandleInterval};
+use crate::orderbook::conflate::{merge_ok, throttle};
+use crate::orderbook::depth::{DepthUpdate, DepthView};
+use crate::orderbook::database::{BookSnapshot, HistoryData, Market};
+use crate::orderbook::matcher::{MatchResult, NewOrder};
+use crate::orderbook::error::OrderBookError;
+use crate::orderbook::exchange::Exchange;
+use crate::orderbook::history::{DealFilter, page};
+use crate::orderbook::journal::JournalEntry;
+use crate::orderbook::simple_broker::SubscriberLag;
+use crate::orderbook::stop_book::{StopBook, StopOrder};
+use crate::orderbook::ticker::Ticker;
 use crate::orderbook::types::date_time::MyDateTime;
-use crate::orderbook::types::slice_display::sorted_slice;
 use crate::orderbook::types::uuid::MyUuid;
 
-#[derive(Enum, Copy, Clone, Eq, PartialEq)]
-pub enum OrderKind {
-    Buy,
-    Sell,
+const DEFAULT_LIMIT: usize = 100;
+
+fn exchange<'a>(ctx: &Context<'a>) -> FieldResult<&'a Arc<Exchange>> {
+    ctx.data::<Arc<Exchange>>()
 }
 
 pub(crate) struct QueryRoot;
@@ -34,20 +40,206 @@ impl QueryRoot {
     pub(crate) async fn orderbook(
         &self,
         ctx: &Context<'_>,
-    ) -> FieldResult<OrderBook> {
-        Ok(ORDERBOOK_STATE.lock().unwrap().orderbook.clone())
+        symbol: String,
+    ) -> FieldResult<OrderBookView> {
+        Ok(OrderBookView::new(exchange(ctx)?.market(&symbol).map_err(|e| e.extend())?))
     }
+    /// Retained deals, newest first
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn history(
         &self,
         ctx: &Context<'_>,
-    ) -> FieldResult<VecDeque<Deal>> {
-        Ok(HISTORY_STATE.lock().unwrap().deals_history.clone())
+        symbol: String,
+        filter: Option<DealFilter>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> FieldResult<Connection<usize, Deal>> {
+        let market = exchange(ctx)?.market(&symbol).map_err(|e| e.extend())?;
+        let filter = filter.unwrap_or_default();
+        connection::query(after, before, first, last, |after, before, first, last| async move {
+            market.read(move |state| page(&state.history.deals_history, &filter, after, before, first, last))
+                .await
+                .map_err(|e| e.extend())
+        }).await
+    }
+    /// Last price, top of the book and 24 hour statistics
+    pub(crate) async fn ticker(
+        &self,
+        ctx: &Context<'_>,
+        symbol: String,
+    ) -> FieldResult<Ticker> {
+        let market = exchange(ctx)?.market(&symbol).map_err(|e| e.extend())?;
+        let ticker = market.ticker.load_full();
+        Ok((*ticker).clone())
+    }
+    /// OHLCV bars opened in [`from`, `to`), oldest first; intervals without deals have no bar
+    pub(crate) async fn candles(
+        &self,
+        ctx: &Context<'_>,
+        symbol: String,
+        interval: CandleInterval,
+        from: Option<MyDateTime<FixedOffset>>,
+        to: Option<MyDateTime<FixedOffset>>,
+    ) -> FieldResult<Vec<Candle>> {
+        let market = exchange(ctx)?.market(&symbol).map_err(|e| e.extend())?;
+        market.read(move |state| state.history.candles.range(interval, from.as_ref().map(|from| &from.0), to.as_ref().map(|to| &to.0)))
+            .await
+            .map_err(|e| e.extend())
+    }
+    /// Queue length and dropped messages of every open subscription
+    pub(crate) async fn subscriber_lag(
+        &self,
+        ctx: &Context<'_>,
+    ) -> FieldResult<Vec<SubscriberLag>> {
+        Ok(exchange(ctx)?.broker.lag())
+    }
+    /// Symbols of all traded instruments
+    pub(crate) async fn markets(
+        &self,
+        ctx: &Context<'_>,
+    ) -> FieldResult<Vec<String>> {
+        Ok(exchange(ctx)?.markets().iter().map(|market| market.symbol.clone()).collect())
+    }
+
+}
+
+pub(crate) struct MutationRoot;
+
+/// Largest quantity an order may have, which keeps the totals of a price level and the traded volumes far from overflowing
+const MAX_QUANTITY: usize = 1_000_000_000;
+
+fn check_quantity(quantity: usize) -> FieldResult<()> {
+    if quantity == 0 || quantity > MAX_QUANTITY {
+        return Err(OrderBookError::InvalidQuantity { max: MAX_QUANTITY }.extend());
     }
+    Ok(())
+}
 
+fn check_price(price: &MyBigUint) -> FieldResult<()> {
+    if price.0.is_zero() {
+        return Err(OrderBookError::InvalidPrice.extend());
+    }
+    Ok(())
 }
 
-#[derive(Clone, Debug, SimpleObject)]
+#[Object]
+impl MutationRoot {
+    /// Match a limit order against the book, resting whatever is left of it
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn place_order(
+        &self,
+        ctx: &Context<'_>,
+        symbol: String,
+        kind: OrderType,
+        price: MyBigUint,
+        quantity: usize,
+        #[graphql(default_with = "TimeInForce::Gtc")] time_in_force: TimeInForce,
+        expires_at: Option<MyDateTime<FixedOffset>>,
+        post_only: Option<PostOnly>,
+        #[graphql(desc = "iceberg: show only this much of the resting quantity at a time")] display_quantity: Option<usize>,
+    ) -> FieldResult<PlaceOrderResult> {
+        check_quantity(quantity)?;
+        check_price(&price)?;
+        let order = NewOrder::limit(kind, price, quantity)
+            .time_in_force(time_in_force, expires_at)
+            .post_only(post_only)
+            .iceberg(display_quantity);
+        let market = exchange(ctx)?.market(&symbol).map_err(|e| e.extend())?;
+        Ok(market.submit(order).await.map_err(|e| e.extend())?.into())
+    }
+    /// Take liquidity at any price until filled or the opposite side is exhausted; never rests
+    pub(crate) async fn place_market_order(
+        &self,
+        ctx: &Context<'_>,
+        symbol: String,
+        kind: OrderType,
+        quantity: usize,
+    ) -> FieldResult<PlaceOrderResult> {
+        check_quantity(quantity)?;
+        let market = exchange(ctx)?.market(&symbol).map_err(|e| e.extend())?;
+        Ok(market.submit(NewOrder::market(kind, quantity)).await.map_err(|e| e.extend())?.into())
+    }
+    /// Park a stop order until a trade reaches `triggerPrice`; it then enters the book as a market
+    /// order, or as a limit order at `limitPrice` if given
+    pub(crate) async fn place_stop_order(
+        &self,
+        ctx: &Context<'_>,
+        symbol: String,
+        kind: OrderType,
+        trigger_price: MyBigUint,
+        quantity: usize,
+        limit_price: Option<MyBigUint>,
+    ) -> FieldResult<StopOrder> {
+        check_quantity(quantity)?;
+        check_price(&trigger_price)?;
+        limit_price.iter().try_for_each(check_price)?;
+        let market = exchange(ctx)?.market(&symbol).map_err(|e| e.extend())?;
+        market.submit_stop(kind, trigger_price, limit_price, quantity).await.map_err(|e| e.extend())
+    }
+    pub(crate) async fn cancel_stop_order(
+        &self,
+        ctx: &Context<'_>,
+        symbol: String,
+        id: usize,
+    ) -> FieldResult<StopOrder> {
+        let market = exchange(ctx)?.market(&symbol).map_err(|e| e.extend())?;
+        market.cancel_stop(id).await.map_err(|e| e.extend())
+    }
+    /// Remove a resting order from the book
+    pub(crate) async fn cancel_order(
+        &self,
+        ctx: &Context<'_>,
+        symbol: String,
+        id: usize,
+        kind: OrderType,
+    ) -> FieldResult<Order> {
+        let market = exchange(ctx)?.market(&symbol).map_err(|e| e.extend())?;
+        market.cancel(kind, id).await.map_err(|e| e.extend())
+    }
+    /// Replace a resting order with a new price and quantity; the replacement is matched as a new order
+    pub(crate) async fn modify_order(
+        &self,
+        ctx: &Context<'_>,
+        symbol: String,
+        id: usize,
+        kind: OrderType,
+        new_price: MyBigUint,
+        new_quantity: usize,
+    ) -> FieldResult<PlaceOrderResult> {
+        check_quantity(new_quantity)?;
+        check_price(&new_price)?;
+        let market = exchange(ctx)?.market(&symbol).map_err(|e| e.extend())?;
+        Ok(market.modify(kind, id, OrderCommons { quantity: new_quantity, price: new_price }).await.map_err(|e| e.extend())?.into())
+    }
+}
+
+#[derive(Clone, SimpleObject)]
+pub(crate) struct PlaceOrderResult {
+    /// resting remainder of the placed order, none if it was filled completely
+    pub(crate) order: Option<Order>,
+    pub(crate) deals: Vec<Deal>,
+    /// quantity that was neither filled nor left resting, e.g. a market order that exhausted the book
+    pub(crate) unfilled_quantity: usize,
+}
+
+impl From<MatchResult> for PlaceOrderResult {
+    fn from(result: MatchResult) -> Self {
+        PlaceOrderResult {
+            order: result.order,
+            deals: result.deals,
+            unfilled_quantity: result.unfilled_quantity,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, SimpleObject)]
 pub(crate) struct Deal {
+    /// per market, in the order the deals happened
+    #[graphql(skip)]
+    pub(crate) seq: usize,
+    pub(crate) symbol: String,
     pub(crate) price: MyBigUint,
     pub(crate) quantity: usize,
     pub(crate) id: MyUuid,
@@ -56,8 +248,10 @@ pub(crate) struct Deal {
 }
 
 impl Deal {
-    pub(crate) fn new(price: MyBigUint, quantity: usize, kind: OrderType) -> Self {
+    pub(crate) fn new(symbol: &str, price: MyBigUint, quantity: usize, kind: OrderType) -> Self {
         Self {
+            seq: 0,
+            symbol: symbol.to_string(),
             price,
             quantity,
             id: MyUuid(Uuid::new_v4()),
@@ -67,16 +261,17 @@ impl Deal {
     }
 }
 
-pub(crate) fn deal(d: Deal) {
-    let dh = &mut HISTORY_STATE.lock().unwrap().deals_history;
-    // keep max size
-    if dh.len() >= HISTORY_CAPACITY {
-        (0..(dh.len() - HISTORY_CAPACITY)).for_each(|_| {
-            dh.pop_back();
-        });
-    }
-    dh.push_front(d.clone());
-    SimpleBroker::publish(d.clone());
+/// Record a deal, numbering it, and let subscribers know
+pub(crate) fn deal(market: &Market, history: &mut HistoryData, sequence: &mut u64, mut d: Deal) -> Deal {
+    d.seq = history.next_seq;
+    history.next_seq += 1;
+    history.deals_history.push_front(d.clone());
+    market.retention.trim(&mut history.deals_history, &d.created_at.0);
+    history.candles.record(&d).into_iter().for_each(|candle| market.publish(candle));
+    history.stats.record(&d);
+    publish_book_update(market, sequence, BookUpdateKind::Trade, None, Some(&d));
+    market.publish(d.clone());
+    d
 }
 
 
@@ -85,36 +280,188 @@ pub(crate) struct SubscriptionRoot;
 
 #[Subscription]
 impl SubscriptionRoot {
-    async fn deals(&self) -> impl Stream<Item = Deal> {
-        SimpleBroker::<Deal>::subscribe()
+    async fn deals(&self, ctx: &Context<'_>, symbol: String) -> FieldResult<impl Stream<Item = FieldResult<Deal>>> {
+        subscribe_market::<Deal>(ctx, symbol)
+    }
+    async fn new_orders(&self, ctx: &Context<'_>, symbol: String) -> FieldResult<impl Stream<Item = FieldResult<OrderAdded>>> {
+        subscribe_market::<OrderAdded>(ctx, symbol)
+    }
+    async fn removed_orders(&self, ctx: &Context<'_>, symbol: String) -> FieldResult<impl Stream<Item = FieldResult<OrderRemoved>>> {
+        subscribe_market::<OrderRemoved>(ctx, symbol)
+    }
+    /// resting orders whose quantity shrank after a partial fill
+    async fn updated_orders(&self, ctx: &Context<'_>, symbol: String) -> FieldResult<impl Stream<Item = FieldResult<OrderUpdated>>> {
+        subscribe_market::<OrderUpdated>(ctx, symbol)
+    }
+    /// every change to the book in the order it happened; apply those after an `orderbook` snapshot's sequence
+    async fn book_updates(&self, ctx: &Context<'_>, symbol: String) -> FieldResult<impl Stream<Item = FieldResult<BookUpdate>>> {
+        subscribe_market::<BookUpdate>(ctx, symbol)
+    }
+    /// The book as it changes, starting with its current state
+    async fn orderbook(
+        &self,
+        ctx: &Context<'_>,
+        symbol: String,
+        #[graphql(desc = "send at most one book per this many milliseconds, skipping the states in between")] throttle_ms: Option<u64>,
+    ) -> FieldResult<impl Stream<Item = FieldResult<OrderBookView>>> {
+        let snapshots = subscribe_market::<SnapshotPublished>(ctx, symbol.clone())?;
+        let market = exchange(ctx)?.market(&symbol).map_err(|e| e.extend())?;
+        let current = SnapshotPublished { snapshot: market.snapshot() };
+        let snapshots = stream::once(future::ready(Ok(current))).chain(snapshots);
+        Ok(throttle(snapshots, throttle_ms, merge_ok(|_, latest| latest))
+            .map(move |published| published.map(|published| OrderBookView { market: market.clone(), snapshot: published.snapshot })))
+    }
+    /// Changes to the top `levels` price levels of both sides, starting with all of them
+    async fn depth_updates(
+        &self,
+        ctx: &Context<'_>,
+        symbol: String,
+        levels: Option<usize>,
+        #[graphql(desc = "send at most one update per this many milliseconds, merging the changes in between")] throttle_ms: Option<u64>,
+    ) -> FieldResult<impl Stream<Item = FieldResult<DepthUpdate>>> {
+        // subscribe before building the view, so that no update falls in between
+        let updates = subscribe_market::<BookUpdate>(ctx, symbol.clone())?;
+        let market = exchange(ctx)?.market(&symbol).map_err(|e| e.extend())?;
+        let levels = levels.unwrap_or(DEFAULT_LIMIT);
+        let (sequence, view) = read_depth(&market, levels).await?;
+        let first = DepthUpdate { symbol: symbol.clone(), sequence, snapshot: true, changes: view.levels() };
+        let changes = stream::unfold((updates, market, view, sequence), move |(mut updates, market, mut view, mut sequence)| {
+            let symbol = symbol.clone();
+            async move {
+                loop {
+                    let update = match updates.next().await? {
+                        Ok(update) => update,
+                        Err(e) => return Some((Err(e), (updates, market, view, sequence))),
+                    };
+                    if update.sequence <= sequence {
+                        continue;
+                    }
+                    // the broker dropped updates: start over from the book as it is now, which has this update in it
+                    if update.sequence > sequence + 1 {
+                        let depth_update = read_depth(&market, levels).await.map(|(now, fresh)| {
+                            view = fresh;
+                            sequence = now;
+                            DepthUpdate { symbol, sequence, snapshot: true, changes: view.levels() }
+                        });
+                        return Some((depth_update, (updates, market, view, sequence)));
+                    }
+                    sequence = update.sequence;
+                    let changes = view.apply(&update);
+                    if !changes.is_empty() {
+                        let depth_update = DepthUpdate { symbol, sequence, snapshot: false, changes };
+                        return Some((Ok(depth_update), (updates, market, view, sequence)));
+                    }
+                }
+            }
+        });
+        Ok(throttle(stream::once(future::ready(Ok(first))).chain(changes), throttle_ms, merge_ok(DepthUpdate::merge)))
     }
-    async fn new_orders(&self) -> impl Stream<Item = OrderAdded> {
-        SimpleBroker::<OrderAdded>::subscribe()
+    /// The ticker each time it changes
+    async fn ticker(&self, ctx: &Context<'_>, symbol: String) -> FieldResult<impl Stream<Item = FieldResult<Ticker>>> {
+        subscribe_market::<Ticker>(ctx, symbol)
     }
-    async fn removed_orders(&self) -> impl Stream<Item = OrderRemoved> {
-        SimpleBroker::<OrderRemoved>::subscribe()
+    /// The current bar of `interval` each time a deal changes it
+    async fn candles(&self, ctx: &Context<'_>, symbol: String, interval: CandleInterval) -> FieldResult<impl Stream<Item = FieldResult<Candle>>> {
+        Ok(subscribe_market::<Candle>(ctx, symbol)?
+            .filter(move |candle| future::ready(candle.as_ref().map_or(true, |candle| candle.interval == interval))))
     }
+    /// stop orders released into the book by a trade
+    async fn triggered_orders(&self, ctx: &Context<'_>, symbol: String) -> FieldResult<impl Stream<Item = FieldResult<StopTriggered>>> {
+        subscribe_market::<StopTriggered>(ctx, symbol)
+    }
+}
+
+/// The top `levels` levels of the book and the sequence they are at, taken on the engine between two commands
+async fn read_depth(market: &Market, levels: usize) -> FieldResult<(u64, DepthView)> {
+    market.read(move |state| (state.sequence, DepthView::new(&state.orderbook, levels))).await.map_err(|e| e.extend())
+}
+
+/// The market's events of type `T`; a subscriber the broker disconnects gets the error as its last item
+fn subscribe_market<T: Sync + Send + Clone + 'static>(ctx: &Context<'_>, symbol: String) -> FieldResult<impl Stream<Item = FieldResult<T>>> {
+    let market = exchange(ctx)?.market(&symbol).map_err(|e| e.extend())?;
+    Ok(market.broker.subscribe::<T>(&market.symbol).map(|event| event.map_err(|e| e.extend())))
 }
 
-#[derive(Hash, Clone, SimpleObject)]
+#[derive(Clone, SimpleObject)]
 pub(crate) struct OrderAdded {
+    pub(crate) symbol: String,
     pub(crate) order: Order
 }
 
-#[derive(Hash, Clone, SimpleObject)]
+#[derive(Clone, SimpleObject)]
 pub(crate) struct OrderRemoved {
+    pub(crate) symbol: String,
+    pub(crate) order: Order
+}
+
+#[derive(Clone, SimpleObject)]
+pub(crate) struct OrderUpdated {
+    pub(crate) symbol: String,
     pub(crate) order: Order
 }
 
-pub(crate) fn publish_order_add(order: &Order) {
-    SimpleBroker::publish(OrderAdded { order: order.clone() });
+#[derive(Clone, SimpleObject)]
+pub(crate) struct StopTriggered {
+    pub(crate) symbol: String,
+    pub(crate) order: StopOrder,
+    /// price of the trade that fired the trigger
+    pub(crate) last_price: MyBigUint,
 }
 
-pub(crate) fn publish_order_remove(order: &Order) {
-    SimpleBroker::publish(OrderRemoved { order: order.clone() });
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub(crate) enum BookUpdateKind {
+    Add,
+    Update,
+    Remove,
+    Trade,
 }
 
-#[derive(Hash, Clone, Eq, PartialEq, Debug, SimpleObject)]
+/// One change to a market's book; sequence numbers go up by one, so a skipped number is a missed update
+#[derive(Clone, SimpleObject, Serialize, Deserialize)]
+pub(crate) struct BookUpdate {
+    pub(crate) symbol: String,
+    pub(crate) sequence: u64,
+    pub(crate) kind: BookUpdateKind,
+    /// the order as added, as left after the change, or as removed; none for trades
+    pub(crate) order: Option<Order>,
+    /// set for trades
+    pub(crate) deal: Option<Deal>,
+}
+
+/// A new book snapshot, published by the engine once per batch of mutations
+#[derive(Clone)]
+pub(crate) struct SnapshotPublished {
+    pub(crate) snapshot: Arc<BookSnapshot>,
+}
+
+pub(crate) fn publish_order_add(market: &Market, sequence: &mut u64, order: &Order) {
+    publish_book_update(market, sequence, BookUpdateKind::Add, Some(order), None);
+    market.publish(OrderAdded { symbol: market.symbol.clone(), order: order.clone() });
+}
+
+pub(crate) fn publish_order_remove(market: &Market, sequence: &mut u64, order: &Order) {
+    publish_book_update(market, sequence, BookUpdateKind::Remove, Some(order), None);
+    market.publish(OrderRemoved { symbol: market.symbol.clone(), order: order.clone() });
+}
+
+pub(crate) fn publish_order_update(market: &Market, sequence: &mut u64, order: &Order) {
+    publish_book_update(market, sequence, BookUpdateKind::Update, Some(order), None);
+    market.publish(OrderUpdated { symbol: market.symbol.clone(), order: order.clone() });
+}
+
+fn publish_book_update(market: &Market, sequence: &mut u64, kind: BookUpdateKind, order: Option<&Order>, deal: Option<&Deal>) {
+    *sequence += 1;
+    let update = BookUpdate { symbol: market.symbol.clone(), sequence: *sequence, kind, order: order.cloned(), deal: deal.cloned() };
+    market.journal(|| JournalEntry::Book(update.clone()));
+    market.publish(update);
+}
+
+pub(crate) fn publish_stop_triggered(market: &Market, order: &StopOrder, last_price: &MyBigUint) {
+    market.journal(|| JournalEntry::StopTriggered { order: order.clone() });
+    market.publish(StopTriggered { symbol: market.symbol.clone(), order: order.clone(), last_price: last_price.clone() });
+}
+#[derive(Hash, Clone, Eq, PartialEq, Debug, SimpleObject, Serialize, Deserialize)]
 pub(crate) struct OrderCommons {
     pub(crate) quantity: usize,
     pub(crate) price: MyBigUint
@@ -132,11 +479,25 @@ impl fmt::Display for Order {
     }
 }
 
-#[derive(Hash, Clone, Debug, SimpleObject)]
+#[derive(Clone, Debug, SimpleObject, Serialize, Deserialize)]
 pub(crate) struct Order {
     pub(crate) id: usize, // we may want to stricten it to newtype
     pub(crate) data: OrderCommons,
     pub(crate) kind: OrderType,
+    /// arrival sequence number, lower is older
+    #[graphql(skip)]
+    pub(crate) seq: u64,
+    /// set for good-till-date orders
+    pub(crate) expires_at: Option<MyDateTime<FixedOffset>>,
+    /// iceberg slice size; `data.quantity` is only the visible part
+    #[graphql(skip)]
+    pub(crate) display_quantity: Option<usize>,
+    /// iceberg reserve not shown in the book
+    #[graphql(skip)]
+    pub(crate) hidden_quantity: usize,
+    /// maker-only mode it was placed with, kept for amendments
+    #[serde(default)]
+    pub(crate) post_only: Option<PostOnly>,
 }
 
 impl PartialEq for Order {
@@ -147,52 +508,187 @@ impl PartialEq for Order {
 
 impl Eq for Order {}
 
-impl Ord for Order {
-    fn cmp(&self, other: &Self) -> Ordering {
-        if self.kind != other.kind { // incomparable
-            panic!("Incomparable orders (Buy and Sell)");
+/// Price-time ordered book; orders are keyed by an id that is never reused
+#[derive(Clone)]
+pub(crate) struct OrderBook {
+    pub(crate) bids: BookSide,
+    pub(crate) asks: BookSide,
+    /// stop orders waiting for their trigger price
+    pub(crate) stops: StopBook,
+    /// next order id to hand out
+    pub(crate) next_id: usize,
+    /// next arrival sequence number to hand out
+    pub(crate) next_seq: u64,
+}
+
+impl OrderBook {
+    pub(crate) fn side(&self, kind: OrderType) -> &BookSide {
+        match kind {
+            OrderType::Buy => &self.bids,
+            OrderType::Sell => &self.asks,
         }
-        return if self.kind == OrderType::Sell {
-            other.data.price.0.cmp(&self.data.price.0)
-        } else {
-            self.data.price.0.cmp(&other.data.price.0)
+    }
+
+    pub(crate) fn remove_order(&mut self, kind: OrderType, id: usize) -> Option<Order> {
+        match kind {
+            OrderType::Buy => self.bids.remove(id),
+            OrderType::Sell => self.asks.remove(id),
         }
+    }
 
+    pub(crate) fn remove_expired(&mut self, now: &DateTime<FixedOffset>) -> Vec<Order> {
+        let (expired_bids, expired_asks) = (self.bids.expired(now), self.asks.expired(now));
+        expired_bids.into_iter().filter_map(|id| self.bids.remove(id))
+            .chain(expired_asks.into_iter().filter_map(|id| self.asks.remove(id)))
+            .collect()
     }
 }
 
-impl PartialOrd for Order {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
+/// A market's book as seen by GraphQL: one snapshot, so all fields agree with each other
+pub(crate) struct OrderBookView {
+    market: Arc<Market>,
+    snapshot: Arc<BookSnapshot>,
 }
 
-#[derive(Clone)]
-pub(crate) struct OrderBook {
-    pub(crate) bids: BinaryHeap<Order>,
-    pub(crate) asks: BinaryHeap<Order>,
-    pub(crate) bid_map: Slab<OrderCommons>,
-    pub(crate) ask_map: Slab<OrderCommons>,
+impl OrderBookView {
+    fn new(market: Arc<Market>) -> Self {
+        let snapshot = market.snapshot();
+        OrderBookView { market, snapshot }
+    }
 }
 
-#[Object]
-impl OrderBook {
+#[Object(name = "OrderBook")]
+impl OrderBookView {
+    async fn symbol(&self) -> &str {
+        &self.market.symbol
+    }
+    /// sequence number of the last `bookUpdates` event this book reflects
+    async fn sequence(&self) -> u64 {
+        self.snapshot.sequence
+    }
     async fn bids_total(&self) -> usize {
-        self.bids.len()
+        self.snapshot.orderbook.bids.len()
     }
     async fn bids(&self, limit: Option<usize>) -> Vec<Order> {
-        sorted_slice(&self.bids, limit)
+        top_orders(&self.snapshot.orderbook, OrderType::Buy, limit)
     }
     async fn asks_total(&self) -> usize {
-        self.asks.len()
+        self.snapshot.orderbook.asks.len()
     }
     async fn asks(&self, limit: Option<usize>) -> Vec<Order> {
-        sorted_slice(&self.asks, limit)
+        top_orders(&self.snapshot.orderbook, OrderType::Sell, limit)
+    }
+    /// stop orders waiting for their trigger, nearest triggers first
+    async fn stop_orders(&self) -> Vec<StopOrder> {
+        self.snapshot.orderbook.stops.orders().cloned().collect()
     }
+    /// Aggregated price levels of one side, best first
+    async fn depth(&self, levels: Option<usize>, side: OrderType) -> Vec<DepthLevel> {
+        let mut cumulative_quantity: usize = 0;
+        self.snapshot.orderbook.side(side).levels().take(levels.unwrap_or(DEFAULT_LIMIT)).map(|(price, level)| {
+            cumulative_quantity = cumulative_quantity.saturating_add(level.quantity);
+            DepthLevel {
+                price: price.clone(),
+                quantity: level.quantity,
+                order_count: level.order_count(),
+                cumulative_quantity,
+            }
+        }).collect()
+    }
+}
+
+#[derive(Clone, SimpleObject)]
+pub(crate) struct DepthLevel {
+    pub(crate) price: MyBigUint,
+    /// total quantity resting at this price
+    pub(crate) quantity: usize,
+    pub(crate) order_count: usize,
+    /// quantity at this and all better prices
+    pub(crate) cumulative_quantity: usize,
+}
+
+fn top_orders(book: &OrderBook, kind: OrderType, limit: Option<usize>) -> Vec<Order> {
+    book.side(kind).orders().take(limit.unwrap_or(DEFAULT_LIMIT)).cloned().collect()
 }
 
-#[derive(PartialEq, Hash, Eq, Clone, Copy, Debug, Enum, strum_macros::Display)]
+#[derive(PartialEq, Hash, Eq, Clone, Copy, Debug, Enum, Serialize, Deserialize, strum_macros::Display)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub(crate) enum OrderType {
     Buy,
     Sell,
 }
+
+impl OrderType {
+    pub(crate) fn opposite(self) -> Self {
+        match self {
+            OrderType::Buy => OrderType::Sell,
+            OrderType::Sell => OrderType::Buy,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub(crate) enum TimeInForce {
+    /// good till cancel: the remainder rests until filled or cancelled
+    Gtc,
+    /// immediate or cancel: the remainder is cancelled
+    Ioc,
+    /// fill or kill: rejected unless it can be filled completely right away
+    Fok,
+    /// good till date: like GTC, but removed from the book at `expiresAt`
+    Gtd,
+}
+
+/// How a maker-only order that would cross the spread is handled
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub(crate) enum PostOnly {
+    /// fail the order with a WOULD_CROSS_SPREAD error
+    Reject,
+    /// rest it one tick behind the best opposite price
+    Reprice,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use async_graphql::Schema;
+    use futures_util::StreamExt;
+    use crate::orderbook::{Exchange, MutationRoot, QueryRoot, Retention, SimpleBroker, SlowConsumerPolicy, SubscriptionRoot};
+
+    #[tokio::test]
+    async fn depth_updates_start_over_after_lost_updates() {
+        let broker = SimpleBroker::new(2, SlowConsumerPolicy::DropOldest);
+        let exchange = Arc::new(Exchange::new(&["MOCK"], broker, Retention::default(), None));
+        let schema = Schema::build(QueryRoot, MutationRoot, SubscriptionRoot).data(exchange).finish();
+        let mut depth = schema.execute_stream("subscription { depthUpdates(symbol: \"MOCK\") { sequence snapshot changes { price quantity } } }");
+        let first = depth.next().await.unwrap().data.into_json().unwrap();
+        assert_eq!(first["depthUpdates"]["snapshot"], true);
+        // more updates than the subscriber's queue holds
+        for price in 1..=5 {
+            let mutation = format!("mutation {{ placeOrder(symbol: \"MOCK\", kind: BUY, price: \"{}\", quantity: 1) {{ __typename }} }}", price);
+            assert!(schema.execute(mutation).await.errors.is_empty());
+        }
+        let next = depth.next().await.unwrap().data.into_json().unwrap();
+        assert_eq!(next["depthUpdates"]["snapshot"], true);
+        assert_eq!(next["depthUpdates"]["sequence"], 5);
+        assert_eq!(next["depthUpdates"]["changes"].as_array().unwrap().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn zero_prices_are_rejected() {
+        let exchange = Arc::new(Exchange::new(&["MOCK"], SimpleBroker::new(16, SlowConsumerPolicy::DropOldest), Retention::default(), None));
+        let schema = Schema::build(QueryRoot, MutationRoot, SubscriptionRoot).data(exchange).finish();
+        let mutations = [
+            "mutation { placeOrder(symbol: \"MOCK\", kind: BUY, price: \"0\", quantity: 1) { __typename } }",
+            "mutation { placeStopOrder(symbol: \"MOCK\", kind: SELL, triggerPrice: \"5\", quantity: 1, limitPrice: \"0\") { __typename } }",
+            "mutation { modifyOrder(symbol: \"MOCK\", id: 0, kind: BUY, newPrice: \"0\", newQuantity: 1) { __typename } }",
+        ];
+        for mutation in mutations {
+            let response = schema.execute(mutation).await;
+            let code = response.errors[0].extensions.as_ref().and_then(|extensions| extensions.get("code")).cloned();
+            assert_eq!(code, Some(async_graphql::Value::from("INVALID_PRICE")), "{}", mutation);
+        }
+    }
+}
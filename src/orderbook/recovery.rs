@@ -0,0 +1,392 @@
+use std::cmp::max;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::orderbook::candles::Candles;
+use crate::orderbook::database::MarketState;
+use crate::orderbook::journal::{Journal, JournalConfig, JournalEntry, JournalPosition};
+use crate::orderbook::matcher::now;
+use crate::orderbook::model::{BookUpdateKind, Deal, Order, OrderBook, OrderType};
+use crate::orderbook::book_side::BookSide;
+use crate::orderbook::retention::Retention;
+use crate::orderbook::stop_book::{StopBook, StopOrder};
+use crate::orderbook::ticker::DealStats;
+use crate::orderbook::types::big_uint::MyBigUint;
+
+/// A market's state as of a point in its journal
+#[derive(Serialize, Deserialize)]
+pub(crate) struct MarketSnapshot {
+    journal: JournalPosition,
+    sequence: u64,
+    next_id: usize,
+    next_seq: u64,
+    orders: Vec<Order>,
+    stops: Vec<StopOrder>,
+    last_price: Option<MyBigUint>,
+    /// newest first
+    deals: VecDeque<Deal>,
+    next_deal_seq: usize,
+    candles: Candles,
+    stats: DealStats,
+}
+
+impl MarketSnapshot {
+    pub(crate) fn of(state: &MarketState, journal: JournalPosition) -> Self {
+        let book = &state.orderbook;
+        MarketSnapshot {
+            journal,
+            sequence: state.sequence,
+            next_id: book.next_id,
+            next_seq: book.next_seq,
+            orders: book.bids.orders().chain(book.asks.orders()).cloned().collect(),
+            stops: book.stops.orders().cloned().collect(),
+            last_price: book.stops.last_price().cloned(),
+            deals: state.history.deals_history.clone(),
+            next_deal_seq: state.history.next_seq,
+            candles: state.history.candles.clone(),
+            stats: state.history.stats.clone(),
+        }
+    }
+
+    fn restore(self) -> MarketState {
+        let mut state = MarketState::new();
+        let book = &mut state.orderbook;
+        book.next_id = self.next_id;
+        book.next_seq = self.next_seq;
+        self.orders.into_iter().for_each(|order| side(book, order.kind).insert(order));
+        book.stops = StopBook::restore(self.stops, self.last_price);
+        state.sequence = self.sequence;
+        state.history.deals_history = self.deals;
+        state.history.next_seq = self.next_deal_seq;
+        state.history.candles = self.candles;
+        state.history.stats = self.stats;
+        state
+    }
+}
+
+fn side(book: &mut OrderBook, kind: OrderType) -> &mut BookSide {
+    match kind {
+        OrderType::Buy => &mut book.bids,
+        OrderType::Sell => &mut book.asks,
+    }
+}
+
+/// Rebuild a market from its latest snapshot and the journal after it, and open the journal to carry on
+pub(crate) fn recover(config: &JournalConfig, symbol: &str, retention: &Retention) -> io::Result<(MarketState, Journal)> {
+    let snapshot_path = config.path(symbol, "snapshot");
+    if config.fresh {
+        set_aside(&config.path(symbol, "journal"))?;
+        set_aside(&snapshot_path)?;
+    }
+    let mut snapshot = read_snapshot(&snapshot_path)?;
+    let journal_path = config.path(symbol, "journal");
+    if let Some(snapshot) = snapshot.as_mut().filter(|snapshot| file_length(&journal_path) < snapshot.journal.offset) {
+        // the journal lost records the snapshot covers and cannot go on; keep it for a look and start a new one after the snapshot
+        eprintln!("{}: ends before byte {} where the snapshot left it, starting a new journal", journal_path.display(), snapshot.journal.offset);
+        set_aside(&journal_path)?;
+        snapshot.journal.offset = 0;
+        write_snapshot(config, symbol, snapshot)?;
+    }
+    let from = snapshot.as_ref().map_or_else(JournalPosition::default, |snapshot| snapshot.journal);
+    let (journal, records) = Journal::open(config, symbol, from)?;
+    let mut state = snapshot.map_or_else(MarketState::new, MarketSnapshot::restore);
+    // deals that leave the history again were archived the first time round, before the records that evicted them were committed
+    let retention = Retention { archive: None, ..retention.clone() };
+    records.into_iter().for_each(|record| replay(&mut state, record.entry, &retention));
+    Ok((state, journal))
+}
+
+/// Redo the effect of one journal entry; the matcher is not run again, its outcome is in the entries
+fn replay(state: &mut MarketState, entry: JournalEntry, retention: &Retention) {
+    let book = &mut state.orderbook;
+    match entry {
+        JournalEntry::Book(update) => {
+            state.sequence = update.sequence;
+            match (update.kind, update.order, update.deal) {
+                (BookUpdateKind::Add, Some(order), _) => {
+                    book.next_id = max(book.next_id, order.id + 1);
+                    book.next_seq = max(book.next_seq, order.seq + 1);
+                    side(book, order.kind).insert(order);
+                }
+                // a fill or an iceberg slice, which may have a new arrival sequence
+                (BookUpdateKind::Update, Some(order), _) => {
+                    book.next_seq = max(book.next_seq, order.seq + 1);
+                    let side = side(book, order.kind);
+                    side.remove(order.id);
+                    side.insert(order);
+                }
+                (BookUpdateKind::Remove, Some(order), _) => {
+                    book.remove_order(order.kind, order.id);
+                }
+                (BookUpdateKind::Trade, _, Some(d)) => {
+                    let history = &mut state.history;
+                    history.next_seq = d.seq + 1;
+                    history.candles.record(&d);
+                    history.stats.record(&d);
+                    history.deals_history.push_front(d.clone());
+                    retention.trim(&mut history.deals_history, &d.created_at.0);
+                    book.stops.record_trade(&d.price);
+                    // what the triggered stops did follows in the journal
+                    while book.stops.next_pending().is_some() {}
+                }
+                _ => {}
+            }
+        }
+        JournalEntry::StopPlaced { order } => {
+            book.next_id = max(book.next_id, order.id + 1);
+            book.next_seq = max(book.next_seq, order.seq + 1);
+            book.stops.insert(order);
+            while book.stops.next_pending().is_some() {}
+        }
+        JournalEntry::StopCancelled { id } => {
+            book.stops.remove(id);
+        }
+        JournalEntry::OrderAccepted { .. }
+        | JournalEntry::OrderCancelled { .. }
+        | JournalEntry::OrderModified { .. }
+        | JournalEntry::OrdersExpired { .. }
+        | JournalEntry::StopTriggered { .. } => {}
+    }
+}
+
+fn read_snapshot(path: &Path) -> io::Result<Option<MarketSnapshot>> {
+    match File::open(path) {
+        Ok(file) => Ok(Some(serde_json::from_reader(BufReader::new(file))?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replace the market's snapshot; it is written to a separate file first so that a crash leaves the previous one whole
+pub(crate) fn write_snapshot(config: &JournalConfig, symbol: &str, snapshot: &MarketSnapshot) -> io::Result<()> {
+    let path = config.path(symbol, "snapshot");
+    let partial = config.path(symbol, "snapshot.partial");
+    let mut out = BufWriter::new(File::create(&partial)?);
+    serde_json::to_writer(&mut out, snapshot)?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&partial, &path)?;
+    File::open(&config.dir)?.sync_all()
+}
+
+/// Length of a file, 0 for a missing one
+fn file_length(path: &Path) -> u64 {
+    fs::metadata(path).map_or(0, |metadata| metadata.len())
+}
+
+/// Keep a file out of the way under a name with the current time
+fn set_aside(path: &Path) -> io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", now().format("%Y%m%dT%H%M%S")));
+    fs::rename(path, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use num_bigint::BigUint;
+    use serde_json::Value;
+    use super::*;
+    use crate::orderbook::database::Market;
+    use crate::orderbook::journal::FsyncPolicy;
+    use crate::orderbook::matcher::NewOrder;
+    use crate::orderbook::model::OrderCommons;
+    use crate::orderbook::retention::DealArchive;
+    use crate::orderbook::simple_broker::{SimpleBroker, SlowConsumerPolicy};
+
+    const SYMBOL: &str = "TEST";
+
+    fn price(price: u32) -> MyBigUint {
+        MyBigUint(BigUint::from(price))
+    }
+
+    fn config_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("orderbook-{}-{}", name, std::process::id()))
+    }
+
+    /// An empty journal directory of its own for each test
+    fn config(name: &str) -> JournalConfig {
+        let dir = config_dir(name);
+        fs::remove_dir_all(&dir).ok();
+        JournalConfig { dir, fsync: FsyncPolicy::Always, fresh: false }
+    }
+
+    fn open(config: &JournalConfig) -> Arc<Market> {
+        let (state, journal) = recover(config, SYMBOL, &Retention::default()).unwrap();
+        Market::new(SYMBOL, SimpleBroker::new(1024, SlowConsumerPolicy::DropOldest), Retention::default(), state, Some(journal))
+    }
+
+    /// Resting orders, an iceberg, fills, an amendment, a cancel, and a stop that triggers next to one that is cancelled
+    async fn trade(market: &Market) {
+        let resting = market.submit(NewOrder::limit(OrderType::Sell, price(105), 10)).await.unwrap().order.unwrap();
+        market.submit(NewOrder::limit(OrderType::Sell, price(110), 5).iceberg(Some(2))).await.unwrap();
+        let bid = market.submit(NewOrder::limit(OrderType::Buy, price(100), 4)).await.unwrap().order.unwrap();
+        market.submit(NewOrder::limit(OrderType::Buy, price(106), 6)).await.unwrap();
+        market.submit_stop(OrderType::Buy, price(107), None, 2).await.unwrap();
+        let stop = market.submit_stop(OrderType::Sell, price(90), None, 1).await.unwrap();
+        market.cancel_stop(stop.id).await.unwrap();
+        market.modify(OrderType::Sell, resting.id, OrderCommons { price: price(105), quantity: 3 }).await.unwrap();
+        market.cancel(OrderType::Buy, bid.id).await.unwrap();
+        market.submit(NewOrder::market(OrderType::Buy, 4)).await.unwrap();
+    }
+
+    /// What a snapshot keeps of the market, in a comparable form
+    fn saved(state: &MarketState) -> Value {
+        serde_json::to_value(MarketSnapshot::of(state, JournalPosition::default())).unwrap()
+    }
+
+    async fn current(market: &Market) -> Value {
+        market.read(saved).await.unwrap()
+    }
+
+    fn recovered(config: &JournalConfig) -> Value {
+        saved(&recover(config, SYMBOL, &Retention::default()).unwrap().0)
+    }
+
+    async fn take_snapshot(config: &JournalConfig, market: &Arc<Market>) {
+        let position = market.clone();
+        let snapshot = market.read(move |state| position.sync_journal().unwrap().map(|journal| MarketSnapshot::of(state, journal))).await.unwrap();
+        write_snapshot(config, SYMBOL, &snapshot.unwrap()).unwrap();
+    }
+
+    fn journal_length(config: &JournalConfig) -> u64 {
+        fs::metadata(config.path(SYMBOL, "journal")).unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn evicted_deals_are_archived_by_the_time_their_batch_is_journaled() {
+        let config = config("archive");
+        fs::create_dir_all(&config.dir).unwrap();
+        let path = config.dir.join("archive.jsonl");
+        let retention = Retention { max_deals: Some(1), max_age: None, archive: Some(DealArchive::open(path.to_str().unwrap()).unwrap()) };
+        let (state, journal) = recover(&config, SYMBOL, &retention).unwrap();
+        let market = Market::new(SYMBOL, SimpleBroker::new(1024, SlowConsumerPolicy::DropOldest), retention.clone(), state, Some(journal));
+        trade(&market).await;
+        let deals = current(&market).await["deals"].as_array().unwrap().len();
+        let archived = fs::read_to_string(&path).unwrap().lines().count();
+        assert_eq!(deals, 1);
+        assert!(archived > 0);
+        // replay evicts the same deals and does not archive them again
+        let (state, _) = recover(&config, SYMBOL, &retention).unwrap();
+        assert_eq!(state.history.deals_history.len(), 1);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), archived);
+    }
+
+    #[tokio::test]
+    async fn journal_replay_rebuilds_the_market() {
+        let config = config("replay");
+        let market = open(&config);
+        trade(&market).await;
+        let state = current(&market).await;
+        assert!(!state["deals"].as_array().unwrap().is_empty());
+        assert_eq!(state["stops"].as_array().unwrap().len(), 0);
+        assert_eq!(recovered(&config), state);
+    }
+
+    #[tokio::test]
+    async fn snapshot_and_later_records_rebuild_the_market() {
+        let config = config("snapshot");
+        let market = open(&config);
+        trade(&market).await;
+        take_snapshot(&config, &market).await;
+        market.submit(NewOrder::limit(OrderType::Buy, price(104), 3)).await.unwrap();
+        market.submit_stop(OrderType::Sell, price(95), Some(price(94)), 1).await.unwrap();
+        assert_eq!(recovered(&config), current(&market).await);
+    }
+
+    /// Recover after a crash left `tail` at the end of the journal, which is cut off before the journal goes on
+    async fn recover_past_torn_tail(name: &str, tail: &[u8]) {
+        let config = config(name);
+        let market = open(&config);
+        trade(&market).await;
+        let state = current(&market).await;
+        let length = journal_length(&config);
+        drop(market);
+        OpenOptions::new().append(true).open(config.path(SYMBOL, "journal")).unwrap().write_all(tail).unwrap();
+        let market = open(&config);
+        assert_eq!(journal_length(&config), length);
+        assert_eq!(current(&market).await, state);
+        market.submit(NewOrder::limit(OrderType::Sell, price(120), 1)).await.unwrap();
+        assert_eq!(recovered(&config), current(&market).await);
+    }
+
+    #[tokio::test]
+    async fn torn_last_record_is_dropped() {
+        // the header of a record and the first byte of it
+        recover_past_torn_tail("torn", &[50, 0, 0, 0, 1, 2, 3, 4, b'{']).await;
+    }
+
+    #[tokio::test]
+    async fn zero_filled_tail_is_dropped() {
+        recover_past_torn_tail("zeros", &[0; 64]).await;
+    }
+
+    #[tokio::test]
+    async fn last_record_with_a_bad_checksum_is_dropped() {
+        let mut tail = vec![10, 0, 0, 0, 1, 2, 3, 4];
+        tail.extend_from_slice(b"{\"seq\": 99}");
+        recover_past_torn_tail("checksum", &tail).await;
+    }
+
+    #[tokio::test]
+    async fn garbage_length_is_dropped() {
+        recover_past_torn_tail("garbage", &[0xff; 16]).await;
+    }
+
+    #[tokio::test]
+    async fn bad_record_before_sound_ones_stops_recovery() {
+        let config = config("middle");
+        let market = open(&config);
+        trade(&market).await;
+        drop(market);
+        let path = config.path(SYMBOL, "journal");
+        let mut bytes = fs::read(&path).unwrap();
+        // inside the JSON of the first record
+        bytes[12] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+        let error = recover(&config, SYMBOL, &Retention::default()).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    /// Recover after `damage` to the journal of a market with a snapshot: the journal is set aside and a new one follows the snapshot
+    async fn recover_from_damaged_journal(name: &str, damage: impl FnOnce(&Path, u64)) {
+        let config = config(name);
+        let market = open(&config);
+        trade(&market).await;
+        take_snapshot(&config, &market).await;
+        let state = current(&market).await;
+        let length = journal_length(&config);
+        drop(market);
+        damage(&config.path(SYMBOL, "journal"), length);
+        let market = open(&config);
+        assert_eq!(current(&market).await, state);
+        assert_eq!(read_snapshot(&config.path(SYMBOL, "snapshot")).unwrap().unwrap().journal.offset, 0);
+        market.submit(NewOrder::limit(OrderType::Sell, price(120), 1)).await.unwrap();
+        assert!(journal_length(&config) < length);
+        assert_eq!(recovered(&config), current(&market).await);
+    }
+
+    #[tokio::test]
+    async fn journal_shorter_than_the_snapshot_is_set_aside() {
+        recover_from_damaged_journal("short", |path, length| {
+            OpenOptions::new().write(true).open(path).unwrap().set_len(length / 2).unwrap();
+        }).await;
+        let set_aside = fs::read_dir(config_dir("short")).unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("TEST.journal."))
+            .collect::<Vec<_>>();
+        assert_eq!(set_aside.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn missing_journal_starts_over_after_the_snapshot() {
+        recover_from_damaged_journal("missing", |path, _| fs::remove_file(path).unwrap()).await;
+    }
+}
@@ -1,12 +1,9 @@
 use std::cmp::max;
 use num_bigint::BigUint;
 use rand::prelude::ThreadRng;
-use rand::{Rng, RngCore};
-use rand::seq::SliceRandom;
-use tokio::time::Interval;
+use rand::Rng;
 use num_traits::cast::ToPrimitive;
-use crate::orderbook::database::ORDERBOOK_STATE;
-use crate::orderbook::model::Order;
+use crate::orderbook::model::OrderBook;
 use crate::orderbook::types::big_uint::MyBigUint;
 
 const MARGIN: usize = 6;
@@ -15,10 +12,12 @@ const BIDDER_CROWD: usize = 10;
 pub(crate) struct Reporter {
     rng: ThreadRng,
     n: u64,
+    /// drain an overgrown book with market orders instead of marketable limit orders
+    market_orders: bool,
 }
 
 fn price_law(k: u64) -> BigUint {
-    BigUint::from(((((k as f64) * 0.1).sin() + (2 as f64)) * (100 as f64)).floor() as u64)
+    BigUint::from(((((k as f64) * 0.1).sin() + 2_f64) * 100_f64).floor() as u64)
 } // no 0 price;
 
 
@@ -26,6 +25,27 @@ fn price_law(k: u64) -> BigUint {
 pub(crate) struct OrderScaffold {
     pub(crate) price: MyBigUint,
     pub(crate) quantity: usize,
+    /// send as a market order, `price` is then ignored
+    pub(crate) market: bool,
+}
+
+/// The part of a book the reporter steers by
+pub(crate) struct BookTop {
+    bids: usize,
+    asks: usize,
+    best_bid: Option<MyBigUint>,
+    best_ask: Option<MyBigUint>,
+}
+
+impl BookTop {
+    pub(crate) fn of(book: &OrderBook) -> Self {
+        BookTop {
+            bids: book.bids.len(),
+            asks: book.asks.len(),
+            best_bid: book.bids.best_price().cloned(),
+            best_ask: book.asks.best_price().cloned(),
+        }
+    }
 }
 
 pub(crate) struct ReportedScaffolds {
@@ -34,55 +54,61 @@ pub(crate) struct ReportedScaffolds {
 }
 
 impl Reporter {
-    pub fn new() -> Self {
+    pub fn new(market_orders: bool) -> Self {
         Reporter {
             rng: rand::thread_rng(),
             n: 0,
+            market_orders,
         }
     }
     fn price_fluctuation(&mut self) -> usize {
         self.rng.gen_range(0..1) * MARGIN + 1 // no 0 price
     }
-    pub(crate) fn step(&mut self) -> ReportedScaffolds {
+    pub(crate) fn step(&mut self, state: &BookTop) -> ReportedScaffolds {
         let n = self.n;
 
-        let mut scaffolds = vec![0, (self.rng.gen_range(0..1) * BIDDER_CROWD)]
+        let scaffolds = vec![0, (self.rng.gen_range(0..1) * BIDDER_CROWD)]
             .into_iter()
             .map(|i| OrderScaffold {
                 price: MyBigUint(price_law(n + i as u64) + BigUint::from(self.price_fluctuation())),
                 quantity: self.rng.gen_range(1..100),
+                market: false,
             })
             .collect::<Vec<OrderScaffold>>();
         // scaffolds.shuffle(&mut self.rng);
         // prices.sort(); // in case we add fluctuation
-        let middle = (scaffolds.len() / 2);
+        let middle = scaffolds.len() / 2;
 
         let (bids_, asks_) = scaffolds.split_at(middle);
-        let state = &mut ORDERBOOK_STATE.lock().unwrap().orderbook;
-        let diff = state.bids.len() as i32 - state.asks.len() as i32;
+        let diff = state.bids as i32 - state.asks as i32;
         let bids_with_diff_bias = bids_.iter().map(|s| OrderScaffold {
             price: MyBigUint(BigUint::from(max(1, s.price.0.to_i32().unwrap() - diff.to_i32().unwrap()) as u64)),
             quantity: s.quantity,
+            market: false,
         }).collect::<Vec<OrderScaffold>>();
         let asks_with_diff_bias = asks_.iter().map(|s| OrderScaffold {
             price: MyBigUint(BigUint::from(max(1, s.price.0.to_i32().unwrap() + diff.to_i32().unwrap()) as u64)),
             quantity: s.quantity,
+            market: false,
         }).collect::<Vec<OrderScaffold>>();
+        let market_orders = self.market_orders;
         let bids = bids_with_diff_bias.iter().map(|s| OrderScaffold {
             // buy if orderbook too big artificiall
-            price: if state.asks.len() < 50 {s.price.clone()} else { state.asks.peek().unwrap().data.price.clone() },
+            price: if state.asks < 50 {s.price.clone()} else { state.best_ask.clone().unwrap() },
             quantity: s.quantity,
+            market: market_orders && state.asks >= 50,
         }).collect::<Vec<OrderScaffold>>();
         let asks = asks_with_diff_bias.iter().map(|s| OrderScaffold {
             // sell if orderbook too big artificiall
-            price: if state.bids.len() < 50 {s.price.clone()} else { state.bids.peek().unwrap().data.price.clone() },
+            price: if state.bids < 50 {s.price.clone()} else { state.best_bid.clone().unwrap() },
             quantity: s.quantity,
+            market: market_orders && state.bids >= 50,
         }).collect::<Vec<OrderScaffold>>();
         self.n = self.n.wrapping_add(1);
         // self.diff = self.diff.wrapping_add(bids.iter().map(|s| s.quantity as i32 * &s.price.0.to_i32().unwrap()).sum::<i32>() - asks.iter().map(|s| s.quantity as i32 * &s.price.0.to_i32().unwrap()).sum::<i32>());
         ReportedScaffolds {
-            bids: Vec::from(bids),
-            asks: Vec::from(asks),
+            bids,
+            asks,
         }
     }
 
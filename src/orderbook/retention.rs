@@ -0,0 +1,80 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Duration, FixedOffset};
+use crate::orderbook::model::Deal;
+
+/// How much deal history a market keeps; deals past either limit leave the history, into the archive if there is one
+#[derive(Clone)]
+pub struct Retention {
+    pub max_deals: Option<usize>,
+    pub max_age: Option<Duration>,
+    pub archive: Option<DealArchive>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            max_deals: Some(10_000),
+            max_age: None,
+            archive: None,
+        }
+    }
+}
+
+impl Retention {
+    /// Evict from the back of a newest first history until it is within the limits
+    pub(crate) fn trim(&self, deals: &mut VecDeque<Deal>, now: &DateTime<FixedOffset>) {
+        let oldest = self.max_age.map(|max_age| *now - max_age);
+        while let Some(d) = deals.back() {
+            let too_many = self.max_deals.is_some_and(|max_deals| deals.len() > max_deals);
+            let too_old = oldest.is_some_and(|oldest| d.created_at.0 < oldest);
+            if !too_many && !too_old {
+                break;
+            }
+            let d = deals.pop_back().unwrap();
+            if let Some(archive) = &self.archive {
+                archive.store(d);
+            }
+        }
+    }
+}
+
+/// Appends evicted deals to a file, one JSON object per line. Evicted deals are held until the engine that evicted them
+/// commits its batch, and are on disk before the batch's journal records, as replay does not archive them again
+#[derive(Clone)]
+pub struct DealArchive {
+    file: Arc<Mutex<ArchiveFile>>,
+}
+
+struct ArchiveFile {
+    file: File,
+    /// deals evicted since the last commit, one line each
+    pending: Vec<u8>,
+}
+
+impl DealArchive {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(DealArchive { file: Arc::new(Mutex::new(ArchiveFile { file, pending: Vec::new() })) })
+    }
+
+    fn store(&self, d: Deal) {
+        let pending = &mut self.file.lock().unwrap().pending;
+        serde_json::to_writer(&mut *pending, &d).expect("deals are plain data");
+        pending.push(b'\n');
+    }
+
+    /// Write and sync the deals evicted so far; markets share the archive, so this takes those of every market
+    pub(crate) fn commit(&self) -> io::Result<()> {
+        let mut archive = self.file.lock().unwrap();
+        if archive.pending.is_empty() {
+            return Ok(());
+        }
+        let ArchiveFile { file, pending } = &mut *archive;
+        file.write_all(pending)?;
+        pending.clear();
+        file.sync_data()
+    }
+}
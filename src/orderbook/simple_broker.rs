@@ -1,68 +1,258 @@
 // simple broker from the async-graphql Subscriptions example
 
 use std::any::{Any, TypeId};
-use std::collections::HashMap;
-use std::marker::PhantomData;
+use std::collections::{HashMap, VecDeque};
 use std::pin::Pin;
-use std::sync::Mutex;
-use std::task::{Context, Poll};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
 
-use futures_channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
-use futures_util::{Stream, StreamExt};
-use once_cell::sync::Lazy;
+use async_graphql::SimpleObject;
+use futures_util::Stream;
 use slab::Slab;
+use crate::orderbook::error::OrderBookError;
 
-static SUBSCRIBERS: Lazy<Mutex<HashMap<TypeId, Box<dyn Any + Send>>>> = Lazy::new(Default::default);
+/// What to do with a message for a subscriber whose queue is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// discard the oldest queued message
+    DropOldest,
+    /// end the subscription with a SLOW_CONSUMER error
+    Disconnect,
+    /// discard everything queued, keeping only the newest message
+    Conflate,
+}
+
+impl FromStr for SlowConsumerPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(SlowConsumerPolicy::DropOldest),
+            "disconnect" => Ok(SlowConsumerPolicy::Disconnect),
+            "conflate" => Ok(SlowConsumerPolicy::Conflate),
+            _ => Err(format!("unknown slow consumer policy {}, expected drop-oldest, disconnect or conflate", s)),
+        }
+    }
+}
+
+/// Messages waiting for one subscriber
+struct Queue<T> {
+    items: VecDeque<T>,
+    waker: Option<Waker>,
+    disconnected: bool,
+    delivered: u64,
+    dropped: u64,
+}
+
+/// Queue length and losses of one subscription
+#[derive(Clone, SimpleObject)]
+pub(crate) struct SubscriberLag {
+    pub(crate) id: usize,
+    /// market the messages belong to
+    pub(crate) topic: String,
+    /// type of the messages subscribed to
+    pub(crate) event: String,
+    /// messages published but not yet taken by the subscriber
+    pub(crate) queued: usize,
+    pub(crate) capacity: usize,
+    pub(crate) delivered: u64,
+    /// messages discarded because the queue was full
+    pub(crate) dropped: u64,
+    pub(crate) disconnected: bool,
+}
+
+struct Senders<T>(Slab<Arc<Mutex<Queue<T>>>>);
+
+/// Senders of any message type, so that their lag can be read without knowing it
+trait AnySenders: Send {
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn lag(&self, topic: &str, capacity: usize) -> Vec<SubscriberLag>;
+}
+
+impl<T: Sync + Send + Clone + 'static> AnySenders for Senders<T> {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn lag(&self, topic: &str, capacity: usize) -> Vec<SubscriberLag> {
+        let event = std::any::type_name::<T>().rsplit("::").next().unwrap_or_default().to_string();
+        self.0.iter().map(|(id, queue)| {
+            let queue = queue.lock().unwrap();
+            SubscriberLag {
+                id,
+                topic: topic.to_string(),
+                event: event.clone(),
+                queued: queue.items.len(),
+                capacity,
+                delivered: queue.delivered,
+                dropped: queue.dropped,
+                disconnected: queue.disconnected,
+            }
+        }).collect()
+    }
+}
 
-struct Senders<T>(Slab<UnboundedSender<T>>);
+/// Senders by topic and message type, so that a subscriber only ever queues the messages it asked for
+type Subscribers = Arc<Mutex<HashMap<(String, TypeId), Box<dyn AnySenders>>>>;
 
-struct BrokerStream<T: Sync + Send + Clone + 'static>(usize, UnboundedReceiver<T>);
+struct BrokerStream<T: Sync + Send + Clone + 'static> {
+    subscribers: Subscribers,
+    topic: String,
+    id: usize,
+    queue: Arc<Mutex<Queue<T>>>,
+    capacity: usize,
+    /// the disconnect error was sent, the stream is over
+    finished: bool,
+}
 
-fn with_senders<T, F, R>(f: F) -> R
+fn with_senders<T, F, R>(subscribers: &Subscribers, topic: &str, f: F) -> R
     where
         T: Sync + Send + Clone + 'static,
         F: FnOnce(&mut Senders<T>) -> R,
 {
-    let mut map = SUBSCRIBERS.lock().unwrap();
+    let mut map = subscribers.lock().unwrap();
     let senders = map
-        .entry(TypeId::of::<Senders<T>>())
+        .entry((topic.to_string(), TypeId::of::<Senders<T>>()))
         .or_insert_with(|| Box::new(Senders::<T>(Default::default())));
-    f(senders.downcast_mut::<Senders<T>>().unwrap())
+    f(senders.as_any_mut().downcast_mut::<Senders<T>>().unwrap())
 }
 
 impl<T: Sync + Send + Clone + 'static> Drop for BrokerStream<T> {
     fn drop(&mut self) {
-        with_senders::<T, _, _>(|senders| senders.0.remove(self.0));
+        with_senders::<T, _, _>(&self.subscribers, &self.topic, |senders| senders.0.remove(self.id));
     }
 }
 
 impl<T: Sync + Send + Clone + 'static> Stream for BrokerStream<T> {
-    type Item = T;
+    type Item = Result<T, OrderBookError>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        self.1.poll_next_unpin(cx)
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        let capacity = self.capacity;
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(item) = queue.items.pop_front() {
+            queue.delivered += 1;
+            return Poll::Ready(Some(Ok(item)));
+        }
+        if queue.disconnected {
+            drop(queue);
+            self.finished = true;
+            return Poll::Ready(Some(Err(OrderBookError::SlowConsumer { capacity })));
+        }
+        queue.waker = Some(cx.waker().clone());
+        Poll::Pending
     }
 }
 
-/// A simple broker based on memory
-pub struct SimpleBroker<T>(PhantomData<T>);
+/// A simple broker based on memory; clones share their subscribers.
+/// Messages are published on a topic and only reach the subscribers of that topic.
+/// Every subscriber has a queue of `capacity` messages, `policy` says what happens when it is full
+#[derive(Clone)]
+pub struct SimpleBroker {
+    subscribers: Subscribers,
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+}
+
+impl SimpleBroker {
+    pub fn new(capacity: usize, policy: SlowConsumerPolicy) -> Self {
+        SimpleBroker {
+            subscribers: Default::default(),
+            capacity: capacity.max(1),
+            policy,
+        }
+    }
 
-impl<T: Sync + Send + Clone + 'static> SimpleBroker<T> {
-    /// Publish a message that all subscription streams can receive.
-    pub fn publish(msg: T) {
-        with_senders::<T, _, _>(|senders| {
-            for (_, sender) in senders.0.iter_mut() {
-                sender.start_send(msg.clone()).ok();
+    /// Publish a message that all subscription streams of `topic` can receive.
+    pub fn publish<T: Sync + Send + Clone + 'static>(&self, topic: &str, msg: T) {
+        with_senders::<T, _, _>(&self.subscribers, topic, |senders| {
+            for (_, queue) in senders.0.iter() {
+                let mut queue = queue.lock().unwrap();
+                if queue.disconnected {
+                    continue;
+                }
+                if queue.items.len() >= self.capacity {
+                    match self.policy {
+                        SlowConsumerPolicy::DropOldest => {
+                            queue.items.pop_front();
+                            queue.dropped += 1;
+                        }
+                        SlowConsumerPolicy::Conflate => {
+                            queue.dropped += queue.items.len() as u64;
+                            queue.items.clear();
+                        }
+                        SlowConsumerPolicy::Disconnect => {
+                            queue.dropped += queue.items.len() as u64 + 1;
+                            queue.items.clear();
+                            queue.disconnected = true;
+                            if let Some(waker) = queue.waker.take() {
+                                waker.wake();
+                            }
+                            continue;
+                        }
+                    }
+                }
+                queue.items.push_back(msg.clone());
+                if let Some(waker) = queue.waker.take() {
+                    waker.wake();
+                }
             }
         });
     }
 
-    /// Subscribe to the message of the specified type and returns a `Stream`.
-    pub fn subscribe() -> impl Stream<Item = T> {
-        with_senders::<T, _, _>(|senders| {
-            let (tx, rx) = mpsc::unbounded();
-            let id = senders.0.insert(tx);
-            BrokerStream(id, rx)
+    /// Subscribe to the message of the specified type on `topic` and returns a `Stream`.
+    /// A subscriber disconnected for being too slow gets one error, then the stream ends
+    pub fn subscribe<T: Sync + Send + Clone + 'static>(&self, topic: &str) -> impl Stream<Item = Result<T, OrderBookError>> {
+        with_senders::<T, _, _>(&self.subscribers, topic, |senders| {
+            let queue = Arc::new(Mutex::new(Queue {
+                items: VecDeque::new(),
+                waker: None,
+                disconnected: false,
+                delivered: 0,
+                dropped: 0,
+            }));
+            let id = senders.0.insert(queue.clone());
+            BrokerStream {
+                subscribers: self.subscribers.clone(),
+                topic: topic.to_string(),
+                id,
+                queue,
+                capacity: self.capacity,
+                finished: false,
+            }
         })
     }
-}
\ No newline at end of file
+
+    /// Lag of every open subscription
+    pub(crate) fn lag(&self) -> Vec<SubscriberLag> {
+        let subscribers = self.subscribers.lock().unwrap();
+        let mut lag = subscribers.iter()
+            .flat_map(|((topic, _), senders)| senders.lag(topic, self.capacity))
+            .collect::<Vec<SubscriberLag>>();
+        lag.sort_by(|a, b| a.topic.cmp(&b.topic).then(a.event.cmp(&b.event)).then(a.id.cmp(&b.id)));
+        lag
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topics_have_queues_of_their_own() {
+        let broker = SimpleBroker::new(2, SlowConsumerPolicy::Disconnect);
+        let _quiet = broker.subscribe::<u32>("QUIET");
+        let _busy = broker.subscribe::<u32>("BUSY");
+        (0..5u32).for_each(|n| broker.publish("BUSY", n));
+        broker.publish("QUIET", 1u32);
+        let lag = broker.lag();
+        let busy = lag.iter().find(|lag| lag.topic == "BUSY").unwrap();
+        let quiet = lag.iter().find(|lag| lag.topic == "QUIET").unwrap();
+        assert!(busy.disconnected);
+        assert!(!quiet.disconnected);
+        assert_eq!((quiet.queued, quiet.dropped), (1, 0));
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use async_graphql::SimpleObject;
+use serde::{Deserialize, Serialize};
+use crate::orderbook::model::OrderType;
+use crate::orderbook::types::big_uint::MyBigUint;
+
+/// Conditional order waiting for the last trade price to reach its trigger
+#[derive(Clone, Debug, SimpleObject, Serialize, Deserialize)]
+pub(crate) struct StopOrder {
+    pub(crate) id: usize,
+    pub(crate) kind: OrderType,
+    /// buy stops trigger at or above this price, sell stops at or below
+    pub(crate) trigger_price: MyBigUint,
+    /// limit of the order sent once triggered, none for a stop-market order
+    pub(crate) limit_price: Option<MyBigUint>,
+    pub(crate) quantity: usize,
+    #[graphql(skip)]
+    pub(crate) seq: u64,
+}
+
+/// Stop orders kept apart from the matching book until their trigger is hit
+#[derive(Clone, Default)]
+pub(crate) struct StopBook {
+    orders: HashMap<usize, StopOrder>,
+    /// (trigger, seq, id), so that equal triggers fire oldest first
+    buys: BTreeSet<(MyBigUint, u64, usize)>,
+    sells: BTreeSet<(MyBigUint, u64, usize)>,
+    /// triggered by a trade, with its price, waiting to be fed into the matcher
+    pending: VecDeque<(StopOrder, MyBigUint)>,
+    last_price: Option<MyBigUint>,
+}
+
+impl StopBook {
+    fn triggers(&mut self, kind: OrderType) -> &mut BTreeSet<(MyBigUint, u64, usize)> {
+        match kind {
+            OrderType::Buy => &mut self.buys,
+            OrderType::Sell => &mut self.sells,
+        }
+    }
+
+    /// Stops saved while waiting, with the last trade price they have already seen
+    pub(crate) fn restore(orders: Vec<StopOrder>, last_price: Option<MyBigUint>) -> Self {
+        let mut book = StopBook::default();
+        orders.into_iter().for_each(|order| book.insert(order));
+        book.last_price = last_price;
+        book
+    }
+
+    pub(crate) fn insert(&mut self, order: StopOrder) {
+        let key = (order.trigger_price.clone(), order.seq, order.id);
+        self.triggers(order.kind).insert(key);
+        self.orders.insert(order.id, order);
+        // a stop already through the market fires right away
+        if let Some(last_price) = self.last_price.clone() {
+            self.record_trade(&last_price);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, id: usize) -> Option<StopOrder> {
+        let order = self.orders.remove(&id)?;
+        let key = (order.trigger_price.clone(), order.seq, order.id);
+        self.triggers(order.kind).remove(&key);
+        Some(order)
+    }
+
+    /// Stops waiting for their trigger
+    pub(crate) fn len(&self) -> usize {
+        self.orders.len()
+    }
+
+    pub(crate) fn orders(&self) -> impl Iterator<Item = &StopOrder> + '_ {
+        self.buys.iter().chain(self.sells.iter().rev()).map(move |(_, _, id)| &self.orders[id])
+    }
+
+    /// Move the stops triggered by a trade at `price` to the pending queue
+    pub(crate) fn record_trade(&mut self, price: &MyBigUint) {
+        self.last_price = Some(price.clone());
+        let buys = self.buys.iter().take_while(|(trigger, _, _)| trigger <= price);
+        let sells = self.sells.iter().rev().take_while(|(trigger, _, _)| trigger >= price);
+        let triggered = buys.chain(sells).map(|(_, _, id)| *id).collect::<Vec<usize>>();
+        for id in triggered {
+            if let Some(order) = self.remove(id) {
+                self.pending.push_back((order, price.clone()));
+            }
+        }
+    }
+
+    /// The oldest triggered stop and the price of the trade that fired it
+    pub(crate) fn next_pending(&mut self) -> Option<(StopOrder, MyBigUint)> {
+        self.pending.pop_front()
+    }
+
+    pub(crate) fn last_price(&self) -> Option<&MyBigUint> {
+        self.last_price.as_ref()
+    }
+}
@@ -0,0 +1,138 @@
+use std::cmp::{max, min};
+use std::collections::VecDeque;
+use async_graphql::SimpleObject;
+use chrono::{DateTime, FixedOffset};
+use num_bigint::BigUint;
+use num_traits::{ToPrimitive, Zero};
+use serde::{Deserialize, Serialize};
+use crate::orderbook::model::{Deal, OrderBook};
+use crate::orderbook::types::big_uint::MyBigUint;
+
+const WINDOW_SECONDS: i64 = 24 * 60 * 60;
+/// the window moves by whole buckets
+const BUCKET_SECONDS: i64 = 60;
+
+/// Deals of one minute
+#[derive(Clone, Serialize, Deserialize)]
+struct Bucket {
+    start: i64,
+    open: MyBigUint,
+    high: MyBigUint,
+    low: MyBigUint,
+    volume: usize,
+    /// sum of price * quantity
+    notional: BigUint,
+}
+
+/// Rolling 24 hour statistics of a market's deals, kept per minute so that updates rarely look past the newest one
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub(crate) struct DealStats {
+    buckets: VecDeque<Bucket>,
+    last_price: Option<MyBigUint>,
+    volume: usize,
+    notional: BigUint,
+    high: Option<MyBigUint>,
+    low: Option<MyBigUint>,
+}
+
+impl DealStats {
+    pub(crate) fn record(&mut self, d: &Deal) {
+        let start = d.created_at.0.timestamp().div_euclid(BUCKET_SECONDS) * BUCKET_SECONDS;
+        let notional = &d.price.0 * d.quantity;
+        match self.buckets.back_mut() {
+            Some(bucket) if bucket.start >= start => {
+                bucket.high = max(&bucket.high, &d.price).clone();
+                bucket.low = min(&bucket.low, &d.price).clone();
+                bucket.volume = bucket.volume.saturating_add(d.quantity);
+                bucket.notional += &notional;
+            }
+            _ => self.buckets.push_back(Bucket {
+                start,
+                open: d.price.clone(),
+                high: d.price.clone(),
+                low: d.price.clone(),
+                volume: d.quantity,
+                notional: notional.clone(),
+            }),
+        }
+        self.volume = self.volume.saturating_add(d.quantity);
+        self.notional += notional;
+        self.high = Some(self.high.take().map_or(d.price.clone(), |high| max(high, d.price.clone())));
+        self.low = Some(self.low.take().map_or(d.price.clone(), |low| min(low, d.price.clone())));
+        self.last_price = Some(d.price.clone());
+    }
+
+    /// Drop the minutes that left the window
+    fn roll(&mut self, now: &DateTime<FixedOffset>) {
+        let mut extremes_left = false;
+        while let Some(bucket) = self.buckets.front() {
+            if bucket.start + BUCKET_SECONDS > now.timestamp() - WINDOW_SECONDS {
+                break;
+            }
+            extremes_left |= Some(&bucket.high) == self.high.as_ref() || Some(&bucket.low) == self.low.as_ref();
+            self.volume = self.volume.saturating_sub(bucket.volume);
+            self.notional -= &bucket.notional;
+            self.buckets.pop_front();
+        }
+        if extremes_left {
+            self.high = self.buckets.iter().map(|bucket| &bucket.high).max().cloned();
+            self.low = self.buckets.iter().map(|bucket| &bucket.low).min().cloned();
+        }
+    }
+}
+
+/// Market summary: last trade, top of the book and the last 24 hours
+#[derive(Clone, Debug, PartialEq, SimpleObject)]
+pub(crate) struct Ticker {
+    pub(crate) symbol: String,
+    pub(crate) last_price: Option<MyBigUint>,
+    pub(crate) best_bid: Option<MyBigUint>,
+    pub(crate) best_ask: Option<MyBigUint>,
+    pub(crate) mid: Option<f64>,
+    pub(crate) spread: Option<MyBigUint>,
+    pub(crate) high: Option<MyBigUint>,
+    pub(crate) low: Option<MyBigUint>,
+    /// traded quantity
+    pub(crate) volume: usize,
+    /// volume weighted average price
+    pub(crate) vwap: Option<f64>,
+    /// from the first price of the window to the last price
+    pub(crate) change_percent: Option<f64>,
+}
+
+impl Ticker {
+    pub(crate) fn new(symbol: &str, stats: &mut DealStats, book: &OrderBook, now: &DateTime<FixedOffset>) -> Self {
+        stats.roll(now);
+        let best_bid = book.bids.best_price().cloned();
+        let best_ask = book.asks.best_price().cloned();
+        let (mid, spread) = match (&best_bid, &best_ask) {
+            (Some(bid), Some(ask)) => (
+                Some((to_f64(&bid.0) + to_f64(&ask.0)) / 2.0),
+                Some(MyBigUint(if ask.0 > bid.0 { &ask.0 - &bid.0 } else { BigUint::from(0u32) })),
+            ),
+            _ => (None, None),
+        };
+        let vwap = (stats.volume > 0).then(|| to_f64(&stats.notional) / stats.volume as f64);
+        let change_percent = match (stats.buckets.front(), &stats.last_price) {
+            (Some(first), Some(last)) if !first.open.0.is_zero() => Some((to_f64(&last.0) - to_f64(&first.open.0)) / to_f64(&first.open.0) * 100.0),
+            _ => None,
+        };
+        Ticker {
+            symbol: symbol.to_string(),
+            last_price: stats.last_price.clone(),
+            best_bid,
+            best_ask,
+            mid,
+            spread,
+            high: stats.high.clone(),
+            low: stats.low.clone(),
+            volume: stats.volume,
+            vwap,
+            change_percent,
+        }
+    }
+}
+
+fn to_f64(n: &BigUint) -> f64 {
+    n.to_f64().unwrap_or(f64::INFINITY)
+}
@@ -6,8 +6,10 @@ use std::str::FromStr;
 use async_graphql::{InputValueError, InputValueResult, ScalarType, Value};
 use num_bigint::{BigUint, ParseBigIntError};
 use async_graphql::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error as _;
 
-#[derive(Hash, Eq, PartialEq, Debug, Clone)]
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Debug, Clone)]
 pub(crate) struct MyBigUint(pub(crate) BigUint);
 
 impl fmt::Display for MyBigUint {
@@ -32,6 +34,18 @@ impl FromStr for MyBigUint {
     }
 }
 
+impl Serialize for MyBigUint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MyBigUint {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(D::Error::custom)
+    }
+}
+
 #[Scalar]
 impl ScalarType for MyBigUint {
     fn parse(value: Value) -> InputValueResult<Self> {
@@ -1,9 +1,10 @@
 use std::fmt;
 use std::fmt::Formatter;
-use std::ops::Add;
 use std::str::FromStr;
 use async_graphql::{InputValueError, InputValueResult, ScalarType, Value};
 use async_graphql::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error as _;
 use chrono::{DateTime, FixedOffset, ParseError, TimeZone};
 
 #[derive(Hash, Eq, PartialEq, Debug, Clone)]
@@ -23,6 +24,18 @@ impl FromStr for MyDateTime<FixedOffset> {
     }
 }
 
+impl <T: TimeZone> Serialize for MyDateTime<T> where T::Offset: fmt::Display {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MyDateTime<FixedOffset> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(D::Error::custom)
+    }
+}
+
 #[Scalar]
 impl ScalarType for MyDateTime<FixedOffset> {
     fn parse(value: Value) -> InputValueResult<Self> {
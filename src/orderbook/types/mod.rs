@@ -1,4 +1,3 @@
 pub(crate) mod big_uint;
 pub(crate) mod date_time;
-pub(crate) mod slice_display;
 pub(crate) mod uuid;
\ No newline at end of file
@@ -1,9 +1,10 @@
 use std::fmt;
 use std::fmt::Formatter;
-use std::ops::Add;
 use std::str::FromStr;
 use async_graphql::{InputValueError, InputValueResult, ScalarType, Value};
 use async_graphql::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error as _;
 use uuid::Uuid;
 
 #[derive(Hash, Eq, PartialEq, Debug, Clone)]
@@ -23,6 +24,18 @@ impl FromStr for MyUuid {
     }
 }
 
+impl Serialize for MyUuid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MyUuid {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(D::Error::custom)
+    }
+}
+
 #[Scalar]
 impl ScalarType for MyUuid {
     fn parse(value: Value) -> InputValueResult<Self> {